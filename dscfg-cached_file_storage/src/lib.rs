@@ -2,8 +2,9 @@ extern crate dscfg_server;
 extern crate serde_json;
 extern crate void;
 
-use dscfg_server::{IsFatalError, Storage};
+use dscfg_server::{Change, IsFatalError, Storage};
 use std::path::{Path, PathBuf};
+use std::fmt;
 use std::io;
 use std::fs::File;
 use std::collections::HashMap;
@@ -44,6 +45,18 @@ impl StorageError {
     }
 }
 
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.operation {
+            IoOperation::Open(file) => write!(f, "failed to open {}: {}", file.display(), self.error),
+            IoOperation::Write(file) => write!(f, "failed to write {}: {}", file.display(), self.error),
+            IoOperation::Move(from, to) => write!(f, "failed to move {} to {}: {}", from.display(), to.display(), self.error),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
 impl IsFatalError for StorageError {
     fn is_fatal(&self) -> bool {
         if let IoOperation::Write(_) = self.operation {
@@ -79,6 +92,23 @@ impl CachedFileStorage {
         })
     }
 
+    /// Atomically writes the whole cache to the file.
+    fn store(&self) -> Result<(), StorageError> {
+        self.store_data(&self.data)
+    }
+
+    /// Atomically writes `data` to the file.
+    fn store_data(&self, data: &HashMap<String, serde_json::Value>) -> Result<(), StorageError> {
+        // Make sure the file is closed before renaming.
+        {
+            let mut file = File::create(&self.temp_file).map_err(|err| StorageError::open_error(&self.temp_file, err))?;
+            serde_json::to_writer(&mut file, data).map_err(|err| StorageError::write_error(&self.temp_file, err))?;
+            file.sync_data().map_err(|err| StorageError::write_error(&self.temp_file, err))?;
+        }
+        std::fs::rename(&self.temp_file, &self.file_path).map_err(|err| StorageError::move_error(&self.temp_file, &self.file_path, err))?;
+        Ok(())
+    }
+
     fn temp_file_path(original_path: &Path) -> io::Result<PathBuf> {
         use std::ffi::OsString;
 
@@ -100,25 +130,119 @@ impl Storage for CachedFileStorage {
     fn set(&mut self, key: String, value: serde_json::Value) -> Result<(), Self::SetError> {
         // TODO: restore original state on failure
         self.data.insert(key, value);
-        // Make sure the file is closed before renaming.
-        {
-            let mut file = File::create(&self.temp_file).map_err(|err| StorageError::open_error(&self.temp_file, err))?;
-            serde_json::to_writer(&mut file, &self.data).map_err(|err| StorageError::write_error(&self.temp_file, err))?;
-            file.sync_data().map_err(|err| StorageError::write_error(&self.temp_file, err))?;
-        }
-        std::fs::rename(&self.temp_file, &self.file_path).map_err(|err| StorageError::move_error(&self.temp_file, &self.file_path, err))?;
-        Ok(())
+        self.store()
     }
 
     fn get(&mut self, key: &str) -> Result<Option<serde_json::Value>, Self::GetError> {
         Ok(self.data.get(key).map(Clone::clone))
     }
+
+    fn keys(&mut self, prefix: &str) -> Result<Vec<String>, Self::GetError> {
+        Ok(self.data.keys().filter(|key| key.starts_with(prefix)).cloned().collect())
+    }
+
+    fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<serde_json::Value>>, Self::GetError> {
+        Ok(keys.iter().map(|key| self.data.get(key).cloned()).collect())
+    }
+
+    fn get_prefix(&mut self, prefix: &str) -> Result<Vec<(String, serde_json::Value)>, Self::GetError> {
+        Ok(self.data.iter().filter(|(key, _)| key.starts_with(prefix)).map(|(key, value)| (key.clone(), value.clone())).collect())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, Self::SetError> {
+        if !self.data.contains_key(key) {
+            return Ok(false);
+        }
+
+        // The key is removed from a copy, so that the cache stays unchanged if storing fails.
+        let mut data = self.data.clone();
+        data.remove(key);
+        self.store_data(&data)?;
+        self.data = data;
+        Ok(true)
+    }
+
+    fn apply(&mut self, changes: Vec<Change>) -> Result<(), Self::SetError> {
+        // The changes are applied to a copy, so that the cache stays unchanged if storing fails.
+        let mut data = self.data.clone();
+        for change in changes {
+            match change {
+                Change::Set { key, value } => { data.insert(key, value); },
+                Change::Delete { key } => { data.remove(&key); },
+            }
+        }
+        // All changes are written at once, so the file contains either none or all of them.
+        self.store_data(&data)?;
+        self.data = data;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::CachedFileStorage;
+    use dscfg_server::{Change, Storage};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn remove_persists() {
+        let dir = std::env::temp_dir().join(format!("dscfg-cached_file_storage-remove-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.json");
+
+        let mut storage = CachedFileStorage::load_or_create(&file).unwrap();
+        storage.set("foo".to_owned(), 42.into()).unwrap();
+        storage.set("bar".to_owned(), serde_json::Value::Null).unwrap();
+        assert!(storage.remove("foo").unwrap());
+        assert!(!storage.remove("foo").unwrap());
+
+        let mut storage = CachedFileStorage::load_or_create(&file).unwrap();
+        assert_eq!(storage.get("foo").unwrap(), None);
+        assert_eq!(storage.get("bar").unwrap(), Some(serde_json::Value::Null));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_apply_keeps_data() {
+        let dir = std::env::temp_dir().join(format!("dscfg-cached_file_storage-apply-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.json");
+
+        let mut storage = CachedFileStorage::load_or_create(&file).unwrap();
+        storage.set("foo".to_owned(), 42.into()).unwrap();
+
+        // Storing fails because the temporary file can't be created.
+        std::fs::create_dir(&storage.temp_file).unwrap();
+        let changes = vec![
+            Change::Set { key: "bar".to_owned(), value: 1.into() },
+            Change::Delete { key: "foo".to_owned() },
+        ];
+        assert!(storage.apply(changes).is_err());
+        assert_eq!(storage.get("foo").unwrap(), Some(42.into()));
+        assert_eq!(storage.get("bar").unwrap(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_remove_keeps_data() {
+        let dir = std::env::temp_dir().join(format!("dscfg-cached_file_storage-failed-remove-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.json");
+
+        let mut storage = CachedFileStorage::load_or_create(&file).unwrap();
+        storage.set("foo".to_owned(), 42.into()).unwrap();
+
+        // Storing fails because the temporary file can't be created.
+        std::fs::create_dir(&storage.temp_file).unwrap();
+        assert!(storage.remove("foo").is_err());
+        assert_eq!(storage.get("foo").unwrap(), Some(42.into()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
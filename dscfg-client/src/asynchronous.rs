@@ -0,0 +1,305 @@
+//! Client built on `std::future::Future`
+//!
+//! This module provides the same functionality as the futures 0.1 based `Client`, but its methods
+//! are `async` and take `&mut self`, so it can be used naturally with async/await.
+//!
+//! The client communicates over any `AsyncRead + AsyncWrite` byte stream from the `futures` crate
+//! using the same length-delimited Json encoding as `dscfg_client::new()`, so it's compatible
+//! with existing servers.
+//!
+//! Note that the futures returned by the methods of `Client` should be run to completion. If one
+//! of them is dropped in the middle of communication, the connection may be left in inconsistent
+//! state.
+
+use crate::{json, message, ProtocolError, CompareAndSetResult, TransactionResult};
+use crate::message::{call, Call, Session, UpdateStep};
+use dscfg_proto::{framing, Operation, Reply, Pattern, Expected, Revision, Condition, Change};
+use futures03::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures03::stream::{self, Stream};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::io;
+
+/// Reads single length-delimited Json message.
+///
+/// Returns `None` if the stream ended before the message started.
+async fn read_message<S: AsyncRead + Unpin, T: DeserializeOwned>(stream: &mut S) -> io::Result<Option<T>> {
+    let mut header = [0; framing::HEADER_LEN];
+    let mut filled = 0;
+
+    while filled < header.len() {
+        let read = stream.read(&mut header[filled..]).await?;
+        if read == 0 {
+            return if filled == 0 {
+                Ok(None)
+            } else {
+                Err(io::ErrorKind::UnexpectedEof.into())
+            };
+        }
+        filled += read;
+    }
+
+    let mut body = vec![0; framing::message_len(header)?];
+    stream.read_exact(&mut body).await?;
+
+    framing::decode(&body).map(Some)
+}
+
+/// Writes single length-delimited Json message.
+async fn write_message<S: AsyncWrite + Unpin, T: Serialize>(stream: &mut S, value: &T) -> io::Result<()> {
+    stream.write_all(&framing::encode(value)?).await?;
+    stream.flush().await
+}
+
+/// DSCFG client using `async` methods
+///
+/// This represents a connection to the DSCFG server and allows
+/// manipulating shared configuration as well as receiving notifications
+/// about changes.
+pub struct Client<S, Val = json::Value> {
+    stream: S,
+    session: Session<Val>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin, Val: Serialize + DeserializeOwned> Client<S, Val> {
+    /// Performs the handshake with the server and creates the client.
+    ///
+    /// Fails if the server doesn't support the protocol version used by this crate.
+    pub async fn connect(stream: S) -> Result<Self, ProtocolError<io::Error>> {
+        let mut client = Client {
+            stream,
+            session: Session::new(),
+        };
+
+        let reply = client.request(message::hello()).await?;
+        client.session.welcome(reply)?;
+        Ok(client)
+    }
+
+    /// Returns `true` if the capability was negotiated with the server.
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.session.has_capability(capability)
+    }
+
+    /// Sends the request and waits for the reply.
+    ///
+    /// Fails without sending anything if the operation requires a capability that wasn't
+    /// negotiated. `Error` and `PermissionDenied` replies are turned into the corresponding
+    /// `ProtocolError` variants.
+    async fn request(&mut self, operation: Operation<Val>) -> Result<Reply<Val>, ProtocolError<io::Error>> {
+        let request = self.session.request(operation)?;
+        write_message(&mut self.stream, &request).await.map_err(ProtocolError::Communication)?;
+
+        loop {
+            let response = read_message(&mut self.stream)
+                .await
+                .map_err(ProtocolError::Communication)?
+                .ok_or(ProtocolError::UnexpectedEof)?;
+
+            if let Some(reply) = self.session.reply(request.id, response) {
+                return message::check(reply);
+            }
+        }
+    }
+
+    /// Performs the call and interprets the reply.
+    async fn call<T>(&mut self, call: Call<Val, T, io::Error>) -> Result<T, ProtocolError<io::Error>> {
+        let reply = self.request(call.operation).await?;
+        (call.interpret)(reply)
+    }
+
+    /// Sets the `key` to given `value` and waits for the acknowledgement.
+    pub async fn set<K: Into<String>>(&mut self, key: K, value: Val) -> Result<(), ProtocolError<io::Error>> {
+        self.call(call::set(key.into(), value)).await
+    }
+
+    /// Sends request to set the `key` to given `value` without waiting for the acknowledgement.
+    pub async fn set_no_wait<K: Into<String>>(&mut self, key: K, value: Val) -> io::Result<()> {
+        let request = self.session.request_unchecked(Operation::Set { key: key.into(), value, });
+        write_message(&mut self.stream, &request).await
+    }
+
+    /// Gets the value of the `key` or `None` if it doesn't exist.
+    pub async fn get<K: Into<String>>(&mut self, key: K) -> Result<Option<Val>, ProtocolError<io::Error>> {
+        self.get_with_revision(key).await.map(|(value, _)| value)
+    }
+
+    /// Gets the value of the `key` along with its revision.
+    ///
+    /// The revision can be used with `compare_and_set()`.
+    pub async fn get_with_revision<K: Into<String>>(&mut self, key: K) -> Result<(Option<Val>, Revision), ProtocolError<io::Error>> {
+        self.call(call::get(key.into())).await
+    }
+
+    /// Gets the values of all `keys` at once.
+    ///
+    /// The values are in the same order as `keys` and those that don't exist are `None`.
+    /// Requires `get_many` capability.
+    pub async fn get_many<K: Into<String>>(&mut self, keys: Vec<K>) -> Result<Vec<Option<Val>>, ProtocolError<io::Error>> {
+        self.call(call::get_many(keys.into_iter().map(Into::into).collect())).await
+    }
+
+    /// Gets all keys starting with `prefix` along with their values.
+    ///
+    /// The keys are sorted. Requires `get_many` capability.
+    pub async fn get_prefix<P: Into<String>>(&mut self, prefix: P) -> Result<Vec<(String, Val)>, ProtocolError<io::Error>> {
+        self.call(call::get_prefix(prefix.into())).await
+    }
+
+    /// Sets the `key` to given `value` if the `expected` condition holds.
+    pub async fn compare_and_set<K: Into<String>>(&mut self, key: K, expected: Expected<Val>, value: Val) -> Result<CompareAndSetResult<Val>, ProtocolError<io::Error>> {
+        self.call(call::compare_and_set(key.into(), expected, value)).await
+    }
+
+    /// Atomically updates the value of the `key` using the function `update`.
+    ///
+    /// The function is called with the current value (`None` if the key doesn't exist) and the
+    /// returned value is stored using `compare_and_set()`. If the value was changed by someone else
+    /// in the meantime, the function is called again with the new value, until the update succeeds.
+    ///
+    /// Returns the revision of the stored value.
+    pub async fn update<K: Into<String>, F: FnMut(Option<Val>) -> Val>(&mut self, key: K, mut update: F) -> Result<Revision, ProtocolError<io::Error>> {
+        let key = key.into();
+        let (value, revision) = self.get_with_revision(key.clone()).await?;
+        let mut result = CompareAndSetResult::Conflict { value, revision };
+
+        loop {
+            match message::update_step(&key, result, &mut update) {
+                UpdateStep::Attempt(call) => result = self.call(call).await?,
+                UpdateStep::Done(revision) => return Ok(revision),
+            }
+        }
+    }
+
+    /// Deletes the `key`.
+    ///
+    /// Returns `true` if the key existed.
+    pub async fn delete<K: Into<String>>(&mut self, key: K) -> Result<bool, ProtocolError<io::Error>> {
+        self.call(call::delete(key.into())).await
+    }
+
+    /// Lists keys starting with `prefix`.
+    ///
+    /// The keys are sorted.
+    pub async fn list_keys<P: Into<String>>(&mut self, prefix: P) -> Result<Vec<String>, ProtocolError<io::Error>> {
+        self.call(call::list(prefix.into())).await
+    }
+
+    /// Applies all `changes` atomically if all `conditions` hold.
+    pub async fn transaction(&mut self, conditions: Vec<Condition<Val>>, changes: Vec<Change<Val>>) -> Result<TransactionResult<Val>, ProtocolError<io::Error>> {
+        self.call(call::transaction(conditions, changes)).await
+    }
+
+    /// Subscribes for notifications of changes of values of keys matching the `pattern`.
+    ///
+    /// If `notify_now` is `true`, current values are received as notifications as well.
+    /// Returns `true` if the client wasn't subscribed with the same pattern before.
+    pub async fn subscribe<P: Into<Pattern>>(&mut self, pattern: P, notify_now: bool) -> Result<bool, ProtocolError<io::Error>> {
+        self.call(call::subscribe(pattern.into(), notify_now)).await
+    }
+
+    /// Subscribes for notifications of changes of values of keys matching any of the `patterns`.
+    ///
+    /// If `notify_now` is `true`, the current values of all matching keys are received as
+    /// a single notification. Returns `true` if the client wasn't subscribed with any of the
+    /// patterns before. Fails if the server doesn't support the `subscribe_many` capability.
+    pub async fn subscribe_many<P: Into<Pattern>>(&mut self, patterns: Vec<P>, notify_now: bool) -> Result<bool, ProtocolError<io::Error>> {
+        self.call(call::subscribe_many(patterns.into_iter().map(Into::into).collect(), notify_now)).await
+    }
+
+    /// Cancels the subscription made with the same `pattern`.
+    ///
+    /// Returns `true` if the client was subscribed.
+    pub async fn unsubscribe<P: Into<Pattern>>(&mut self, pattern: P) -> Result<bool, ProtocolError<io::Error>> {
+        self.call(call::unsubscribe(pattern.into())).await
+    }
+
+    /// Waits for the next notification.
+    ///
+    /// All changes made by a single transaction are returned together. Returns `None` if the
+    /// server closed the connection.
+    pub async fn next_changes(&mut self) -> Result<Option<Vec<Change<Val>>>, ProtocolError<io::Error>> {
+        if let Some(changes) = self.session.buffered_changes() {
+            return Ok(Some(changes));
+        }
+
+        loop {
+            let response = read_message(&mut self.stream)
+                .await
+                .map_err(ProtocolError::Communication)?;
+
+            match response {
+                // Replies to requests sent without waiting are skipped.
+                Some(response) => if let Some(changes) = message::notification_changes(response) {
+                    return Ok(Some(changes));
+                },
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns stream of changes the client is subscribed to.
+    ///
+    /// Changes made by a single transaction are yielded one after another. The stream ends when
+    /// the server closes the connection.
+    pub fn notifications(&mut self) -> impl Stream<Item=Result<Change<Val>, ProtocolError<io::Error>>> + '_ {
+        let pending = VecDeque::new();
+
+        stream::unfold((self, pending), |(client, mut pending)| async move {
+            loop {
+                if let Some(change) = pending.pop_front() {
+                    return Some((Ok(change), (client, pending)));
+                }
+
+                match client.next_changes().await {
+                    Ok(Some(changes)) => pending.extend(changes),
+                    Ok(None) => return None,
+                    Err(err) => return Some((Err(err), (client, pending))),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Client;
+    use crate::json;
+    use crate::message::loopback::Loopback;
+    use futures03::executor::block_on;
+
+    #[test]
+    fn set_then_get() {
+        let mut client = block_on(Client::<_, json::Value>::connect(Loopback::default())).unwrap();
+
+        block_on(client.set("a", json::Value::from(1))).unwrap();
+        block_on(client.set_no_wait("b", json::Value::from(2))).unwrap();
+
+        assert_eq!(block_on(client.get("a")).unwrap(), Some(json::Value::from(1)));
+        assert_eq!(block_on(client.get("b")).unwrap(), Some(json::Value::from(2)));
+    }
+
+    #[test]
+    fn get_missing() {
+        let mut client = block_on(Client::<_, json::Value>::connect(Loopback::default())).unwrap();
+
+        assert_eq!(block_on(client.get("missing")).unwrap(), None);
+    }
+
+    #[test]
+    fn update_retries_on_conflict() {
+        let mut loopback = Loopback::default();
+        loopback.concurrent_set = Some(("a".to_owned(), json::Value::from(10)));
+        let mut client = block_on(Client::<_, json::Value>::connect(loopback)).unwrap();
+
+        let mut seen = Vec::new();
+        block_on(client.update("a", |value| {
+            seen.push(value.clone());
+            json::Value::from(value.and_then(|value| value.as_i64()).unwrap_or(0) + 1)
+        })).unwrap();
+
+        assert_eq!(seen, [None, Some(json::Value::from(10))]);
+        assert_eq!(block_on(client.get("a")).unwrap(), Some(json::Value::from(11)));
+    }
+}
@@ -0,0 +1,409 @@
+//! Typed access to configuration
+//!
+//! The `Client` is generic over a single value type, which is usually `json::Value`, so that
+//! values of various types can be stored. This module allows converting the values to Rust types
+//! automatically.
+//!
+//! `Key<T>` is a handle for single key storing values of type `T`. `Binding<T>` maps fields of a
+//! struct to keys. The struct just needs to `#[derive(Deserialize)]` - the names of the fields
+//! (possibly changed by `#[serde(rename = "...")]`) are used as keys. Missing keys are allowed for
+//! fields of type `Option<_>` and fields with `#[serde(default)]`.
+//!
+//! `Client` uses these types in `get_key()`, `set_key()`, `load()` and `watch()` methods. When
+//! using other clients, you can subscribe to `Binding::keys()` and call `Binding::decode()`
+//! yourself.
+
+use crate::{json, ProtocolError};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::Deserializer;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::fmt;
+
+/// Handle for a key storing values of type `T`.
+pub struct Key<T> {
+    name: String,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Key<T> {
+    /// Creates the handle for key `name`.
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        Key {
+            name: name.into(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the name of the key.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<T: DeserializeOwned> Key<T> {
+    /// Converts the value received from the server to `T`.
+    ///
+    /// `Null` is considered to be a missing value unless `T` accepts it.
+    pub fn decode(&self, value: json::Value) -> Result<T, KeyError> {
+        let is_null = value.is_null();
+
+        T::deserialize(value).map_err(|error| KeyError {
+            key: self.name.clone(),
+            kind: if is_null { KeyErrorKind::Missing } else { KeyErrorKind::Invalid(error) },
+        })
+    }
+}
+
+impl<T: serde::Serialize> Key<T> {
+    /// Converts the value to the representation sent to the server.
+    pub fn encode(&self, value: &T) -> Result<json::Value, KeyError> {
+        json::to_value(value).map_err(|error| KeyError {
+            key: self.name.clone(),
+            kind: KeyErrorKind::Invalid(error),
+        })
+    }
+}
+
+impl<T> Clone for Key<T> {
+    fn clone(&self) -> Self {
+        Key::new(self.name.clone())
+    }
+}
+
+impl<T> fmt::Debug for Key<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Key").field(&self.name).finish()
+    }
+}
+
+/// Mapping of fields of struct `T` to keys.
+///
+/// The key of each field is the name of the field prepended with the prefix of the binding.
+pub struct Binding<T> {
+    prefix: String,
+    fields: &'static [&'static str],
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> Binding<T> {
+    /// Creates the binding with keys equal to the names of the fields.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `T` isn't deserialized from a struct.
+    pub fn new() -> Self {
+        Binding::with_prefix(String::new())
+    }
+
+    /// Creates the binding with keys equal to the names of the fields prepended with `prefix`.
+    ///
+    /// E.g. field `port` is bound to the key `net.port` if the prefix is `net.`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `T` isn't deserialized from a struct.
+    pub fn with_prefix<P: Into<String>>(prefix: P) -> Self {
+        let mut fields = None;
+        // The error is always returned, the fields are all we need.
+        let _ = T::deserialize(FieldNames(&mut fields));
+
+        Binding {
+            prefix: prefix.into(),
+            fields: fields.expect("the binding must be a struct"),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the keys the fields are bound to.
+    pub fn keys<'a>(&'a self) -> impl 'a + Iterator<Item=String> {
+        self.fields.iter().map(move |field| self.key(field))
+    }
+
+    /// Creates the struct from the values of the keys.
+    ///
+    /// Keys missing in `values` and keys with `Null` values are considered missing. All keys with
+    /// invalid values are reported. A missing key is reported only if it's the first required key
+    /// which is missing or invalid.
+    pub fn decode(&self, values: &HashMap<String, json::Value>) -> Result<T, Vec<KeyError>> {
+        let mut present = self.fields
+            .iter()
+            .filter_map(|field| values.get(&self.key(field)).filter(|value| !value.is_null()).map(|value| (*field, value)))
+            .collect::<HashMap<_, _>>();
+        let mut errors = Vec::new();
+
+        // When a value is invalid, it's removed and decoding is retried to find other errors.
+        loop {
+            let mut invalid = None;
+            let result = T::deserialize(StructDeserializer {
+                fields: self.fields,
+                values: &present,
+                invalid: &mut invalid,
+            });
+
+            let error = match result {
+                Ok(value) => if errors.is_empty() {
+                    return Ok(value);
+                } else {
+                    return Err(errors);
+                },
+                Err(DecodeError::InvalidValue) => {
+                    let (field, error) = invalid.expect("missing error of invalid value");
+                    present.remove(field);
+                    errors.push(KeyError { key: self.key(field), kind: KeyErrorKind::Invalid(error) });
+                    continue;
+                },
+                Err(DecodeError::Missing(field)) => KeyError { key: self.key(field), kind: KeyErrorKind::Missing },
+                // Errors not caused by a single key are attributed to the prefix.
+                Err(DecodeError::Other(message)) => KeyError { key: self.prefix.clone(), kind: KeyErrorKind::Invalid(de::Error::custom(message)) },
+            };
+
+            // The field was missing because its invalid value was removed.
+            if !errors.iter().any(|reported| reported.key == error.key) {
+                errors.push(error);
+            }
+
+            return Err(errors);
+        }
+    }
+
+    fn key(&self, field: &str) -> String {
+        format!("{}{}", self.prefix, field)
+    }
+}
+
+impl<T> Clone for Binding<T> {
+    fn clone(&self) -> Self {
+        Binding {
+            prefix: self.prefix.clone(),
+            fields: self.fields,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Default for Binding<T> {
+    fn default() -> Self {
+        Binding::new()
+    }
+}
+
+/// Error returned when a value of a key can't be converted.
+#[derive(Debug)]
+pub struct KeyError {
+    /// The key which has missing or invalid value.
+    pub key: String,
+    /// What's wrong with the value.
+    pub kind: KeyErrorKind,
+}
+
+/// Describes what's wrong with a value.
+#[derive(Debug)]
+pub enum KeyErrorKind {
+    /// The key doesn't exist, but the value is required.
+    Missing,
+    /// The value couldn't be converted.
+    Invalid(json::Error),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            KeyErrorKind::Missing => write!(f, "the key {} is missing", self.key),
+            KeyErrorKind::Invalid(error) => write!(f, "the value of the key {} is invalid: {}", self.key, error),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// Error returned when typed operation fails.
+#[derive(Debug)]
+pub enum BindingError<E> {
+    /// Communication with the server failed.
+    Protocol(ProtocolError<E>),
+    /// Some values couldn't be converted.
+    Decode(Vec<KeyError>),
+}
+
+impl<E> From<ProtocolError<E>> for BindingError<E> {
+    fn from(error: ProtocolError<E>) -> Self {
+        BindingError::Protocol(error)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for BindingError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindingError::Protocol(error) => fmt::Display::fmt(error, f),
+            BindingError::Decode(errors) => {
+                write!(f, "failed to decode configuration")?;
+                for error in errors {
+                    write!(f, "; {}", error)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl<E: std::error::Error> std::error::Error for BindingError<E> {}
+
+/// Error used internally while decoding the struct.
+#[derive(Debug)]
+enum DecodeError {
+    Missing(&'static str),
+    // The error itself is stored separately, since it has different type.
+    InvalidValue,
+    Other(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Missing(field) => write!(f, "missing field {}", field),
+            DecodeError::InvalidValue => write!(f, "invalid value"),
+            DecodeError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl de::Error for DecodeError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        DecodeError::Other(message.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        DecodeError::Missing(field)
+    }
+}
+
+/// Deserializer retrieving names of fields of a struct.
+struct FieldNames<'a>(&'a mut Option<&'static [&'static str]>);
+
+impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+    type Error = DecodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("the binding must be a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], _visitor: V) -> Result<V::Value, Self::Error> {
+        *self.0 = Some(fields);
+        Err(de::Error::custom("only the names of the fields are needed"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Deserializer of a struct from values of fields.
+struct StructDeserializer<'a> {
+    fields: &'static [&'static str],
+    values: &'a HashMap<&'static str, &'a json::Value>,
+    // Error of the value which failed to deserialize.
+    invalid: &'a mut Option<(&'static str, json::Error)>,
+}
+
+impl<'de> Deserializer<'de> for StructDeserializer<'de> {
+    type Error = DecodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("the binding must be a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(FieldAccess {
+            fields: self.fields.iter(),
+            values: self.values,
+            current: None,
+            invalid: self.invalid,
+        })
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+struct FieldAccess<'a> {
+    fields: std::slice::Iter<'static, &'static str>,
+    values: &'a HashMap<&'static str, &'a json::Value>,
+    current: Option<(&'static str, &'a json::Value)>,
+    invalid: &'a mut Option<(&'static str, json::Error)>,
+}
+
+impl<'de> MapAccess<'de> for FieldAccess<'de> {
+    type Error = DecodeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        for field in &mut self.fields {
+            if let Some(value) = self.values.get(field) {
+                self.current = Some((field, value));
+                return seed.deserialize((*field).into_deserializer()).map(Some);
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        let (field, value) = self.current.take().expect("value requested before key");
+
+        seed.deserialize(value).map_err(|error| {
+            *self.invalid = Some((field, error));
+            DecodeError::InvalidValue
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Binding, KeyErrorKind};
+    use serde_derive::Deserialize;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize, Debug, Eq, PartialEq)]
+    struct Config {
+        port: u16,
+        hosts: Vec<String>,
+        #[serde(rename = "user_name")]
+        user: Option<String>,
+    }
+
+    fn to_map(values: &[(&str, crate::json::Value)]) -> HashMap<String, crate::json::Value> {
+        values.iter().map(|(key, value)| (key.to_string(), value.clone())).collect()
+    }
+
+    #[test]
+    fn decode() {
+        let binding = Binding::<Config>::with_prefix("net.");
+        assert_eq!(binding.keys().collect::<Vec<_>>(), ["net.port", "net.hosts", "net.user_name"]);
+
+        let values = to_map(&[("net.port", json!(80)), ("net.hosts", json!(["a", "b"])), ("net.user_name", json!(null))]);
+        let config = binding.decode(&values).unwrap();
+        assert_eq!(config, Config { port: 80, hosts: vec!["a".to_owned(), "b".to_owned()], user: None });
+    }
+
+    #[test]
+    fn errors() {
+        let binding = Binding::<Config>::new();
+
+        let values = to_map(&[("port", json!("eighty")), ("user_name", json!(42))]);
+        let errors = binding.decode(&values).unwrap_err();
+        let errors = errors.iter().map(|error| (error.key.as_str(), match error.kind { KeyErrorKind::Missing => false, KeyErrorKind::Invalid(_) => true })).collect::<Vec<_>>();
+        assert_eq!(errors, [("port", true), ("user_name", true)]);
+
+        let values = to_map(&[("port", json!(80))]);
+        let errors = binding.decode(&values).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key, "hosts");
+        assert!(matches!(errors[0].kind, KeyErrorKind::Missing));
+    }
+}
@@ -3,14 +3,42 @@ extern crate futures;
 extern crate tokio_io;
 extern crate serde;
 extern crate serde_json;
+#[cfg(feature = "asynchronous")]
+extern crate futures03;
+#[cfg(feature = "reconnect")]
+extern crate tokio_timer;
+#[cfg(feature = "tcp")]
+extern crate tokio_tcp;
+#[cfg(feature = "tls")]
+pub extern crate dscfg_tls as tls;
+
+#[cfg(feature = "asynchronous")]
+pub mod asynchronous;
+#[cfg(unix)]
+pub mod blocking;
+pub mod binding;
+mod message;
+pub mod mirror;
+pub mod multiplex;
+#[cfg(feature = "reconnect")]
+pub mod reconnect;
 
 pub use dscfg_proto::json;
+pub use dscfg_proto::{Pattern, Expected, Revision, Condition, Change, Entry, ErrorCode, Permission};
+pub use binding::{Key, Binding, KeyError, KeyErrorKind, BindingError};
+pub use mirror::Mirror;
+pub use multiplex::{Handle, Subscription, Driver};
 
 //use tokio_io::{AsyncRead, AsyncWrite};
 use futures::{Stream, Sink, Future};
+use futures::{future, stream};
+use dscfg_proto::{Request, RequestId, Operation, Response, Reply, capability};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::io;
 use tokio_io::{AsyncRead, AsyncWrite};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 
 /// Error returned when DSCFG protocol fails.
 #[derive(Debug)]
@@ -19,10 +47,72 @@ pub enum ProtocolError<E> {
     UnexpectedResponse,
     /// The stream has ended before a message could be fully decoded.
     UnexpectedEof,
+    /// The server doesn't support the version of the protocol used by this client.
+    ///
+    /// `server_version` is the version the server supports.
+    UnsupportedVersion { server_version: u32 },
+    /// The operation requires a capability the server doesn't support.
+    MissingCapability(&'static str),
+    /// The server failed to perform the operation.
+    OperationFailed { code: ErrorCode, message: String },
+    /// The client isn't allowed to access the `key` in the way the operation requires.
+    PermissionDenied { key: String, permission: Permission },
     /// Underlying communication error - e.g. I/O error.
     Communication(E),
 }
 
+impl<E: fmt::Display> fmt::Display for ProtocolError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::UnexpectedResponse => write!(f, "the server sent unexpected response"),
+            ProtocolError::UnexpectedEof => write!(f, "the connection was closed unexpectedly"),
+            ProtocolError::UnsupportedVersion { server_version } => write!(f, "the server supports protocol version {}, but this client uses version {}", server_version, dscfg_proto::PROTOCOL_VERSION),
+            ProtocolError::MissingCapability(capability) => write!(f, "the server doesn't support {}", capability),
+            ProtocolError::OperationFailed { code, message } => write!(f, "the operation failed ({}): {}", code, message),
+            ProtocolError::PermissionDenied { key, permission } => write!(f, "permission to {} {} denied", permission, key),
+            ProtocolError::Communication(err) => write!(f, "communication failed: {}", err),
+        }
+    }
+}
+
+impl<E: std::error::Error> std::error::Error for ProtocolError<E> {}
+
+/// Result of compare-and-set operation.
+#[derive(Debug)]
+pub enum CompareAndSetResult<Val> {
+    /// The value was stored and has the given revision now.
+    Updated(Revision),
+    /// The expected condition didn't hold.
+    ///
+    /// Contains current value (`None` if the key doesn't exist) and revision of the key.
+    Conflict { value: Option<Val>, revision: Revision },
+}
+
+/// Result of a transaction.
+#[derive(Debug)]
+pub enum TransactionResult<Val> {
+    /// All changes were applied and the changed keys have the given revision now.
+    Committed(Revision),
+    /// The condition for `key` didn't hold, so no changes were applied.
+    ///
+    /// Contains current value (`None` if the key doesn't exist) and revision of the key.
+    Conflict { key: String, value: Option<Val>, revision: Revision },
+}
+
+/// Capabilities this client requests during handshake.
+const CAPABILITIES: &[&str] = &[
+    capability::DELETE,
+    capability::LIST,
+    capability::COMPARE_AND_SET,
+    capability::TRANSACTION,
+    capability::SUBSCRIBE_MANY,
+    capability::GET_MANY,
+    capability::PERMISSION_DENIED,
+    capability::NOT_FOUND,
+    capability::ERRORS,
+    capability::CHANGES,
+];
+
 /// DSCFG client
 ///
 /// This represents a connection to the DSCFG server and allows
@@ -31,67 +121,607 @@ pub enum ProtocolError<E> {
 ///
 /// You should usually create it by calling `new()` function of this crate,
 /// but you may use custom stream if you need finer control.
-pub struct Client<C> {
+pub struct Client<C: Stream> {
     connection: C,
+    capabilities: Vec<String>,
+    next_id: RequestId,
+    // Notifications received while waiting for a reply.
+    notifications: VecDeque<C::Item>,
 }
 
-impl<Val: Serialize + for<'a> Deserialize<'a>, E, C: Stream<Item=dscfg_proto::Response<Val>, Error=E> + Sink<SinkItem=dscfg_proto::Request<Val>, SinkError=E>> Client<C> {
+impl<Val: Serialize + for<'a> Deserialize<'a>, E, C: Stream<Item=Response<Val>, Error=E> + Sink<SinkItem=Request<Val>, SinkError=E>> Client<C> {
     /// Intantiates Client using provided custom `Stream + Sink`.
-    pub fn custom(connection: C) -> Self {
-        Client {
+    ///
+    /// The returned future performs the handshake with the server and resolves to `Client`
+    /// if the server supports the protocol version used by this crate.
+    pub fn custom(connection: C) -> impl Future<Item=Self, Error=ProtocolError<E>> {
+        let client = Client {
             connection,
+            capabilities: Vec::new(),
+            next_id: 0,
+            notifications: VecDeque::new(),
+        };
+
+        client
+            .request(message::hello())
+            .and_then(|(reply, mut client)| {
+                client.capabilities = message::welcome(reply)?;
+                Ok(client)
+            })
+    }
+
+    /// Returns `true` if the capability was negotiated with the server.
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|supported| supported == capability)
+    }
+
+    /// Sends the request without waiting for the reply.
+    ///
+    /// Returns the id of the request, so that the reply can be found later.
+    fn send_request(self, operation: Operation<Val>) -> impl Future<Item=(RequestId, Self), Error=E> {
+        let Client { connection, capabilities, next_id, notifications } = self;
+
+        connection
+            .send(Request { id: next_id, operation })
+            .map(move |connection| {
+                let client = Client {
+                    connection,
+                    capabilities,
+                    next_id: next_id.wrapping_add(1),
+                    notifications,
+                };
+
+                (next_id, client)
+            })
+    }
+
+    /// Waits for the reply to the request with given `id`.
+    ///
+    /// Replies to other requests are skipped and notifications are buffered.
+    fn wait_reply(self, id: RequestId) -> impl Future<Item=(Reply<Val>, Self), Error=ProtocolError<E>> {
+        future::loop_fn(self, move |client| {
+            let Client { connection, capabilities, next_id, mut notifications } = client;
+
+            connection
+                .into_future()
+                .map_err(|(err, _)| ProtocolError::Communication(err))
+                .and_then(move |(response, connection)| {
+                    let reply = match response {
+                        Some(Response::Reply { id: reply_id, reply }) => if reply_id == id { Some(reply) } else { None },
+                        Some(notification) => {
+                            notifications.push_back(notification);
+                            None
+                        },
+                        None => return Err(ProtocolError::UnexpectedEof),
+                    };
+
+                    let client = Client { connection, capabilities, next_id, notifications, };
+
+                    match reply {
+                        Some(reply) => Ok(future::Loop::Break((reply, client))),
+                        None => Ok(future::Loop::Continue(client)),
+                    }
+                })
+        })
+    }
+
+    /// Sends the request and waits for the reply.
+    ///
+    /// Fails without sending anything if the operation requires a capability that wasn't
+    /// negotiated. `Error` and `PermissionDenied` replies are turned into the corresponding
+    /// `ProtocolError` variants.
+    fn request(self, operation: Operation<Val>) -> impl Future<Item=(Reply<Val>, Self), Error=ProtocolError<E>> {
+        if let Err(err) = message::check_capability(&self.capabilities, &operation) {
+            return future::Either::A(future::err(err));
         }
+
+        future::Either::B(self.send_request(operation)
+            .map_err(ProtocolError::Communication)
+            .and_then(|(id, client)| client.wait_reply(id))
+            .and_then(|(reply, client)| Ok((message::check(reply)?, client))))
     }
 
-    /// Sends request to set the `key` to given `value`.
+    /// Sends request to set the `key` to given `value` and waits for the acknowledgement.
     ///
-    /// Returns future which resolves to `Client`, if the request succeeded.
-    pub fn set_value(self, key: String, value: Val) -> impl Future<Item=Self, Error=E> {
-        self.connection.send(dscfg_proto::Request::Set { key, value, })
-            .map(|connection| Client { connection, })
+    /// Returns future which resolves to `Client`, if the server stored the value.
+    pub fn set_value(self, key: String, value: Val) -> impl Future<Item=Self, Error=ProtocolError<E>> {
+        self.request(Operation::Set { key, value, })
+            .and_then(|(reply, client)| message::stored(reply).map(|_| client))
+    }
+
+    /// Sends request to set the `key` to given `value` without waiting for the acknowledgement.
+    ///
+    /// Returns future which resolves to `Client` as soon as the request is sent. The client won't
+    /// find out whether the server actually stored the value.
+    pub fn set_value_no_wait(self, key: String, value: Val) -> impl Future<Item=Self, Error=E> {
+        self.send_request(Operation::Set { key, value, })
+            .map(|(_, client)| client)
     }
 
     /// Sends request for getting value of given key and waits for the answer.
     ///
-    /// Returns future which resolves to `(Val, Self)` if successful.
-    pub fn get_value<K: Into<String>>(self, key: K) -> impl Future<Item=(Val, Self), Error=ProtocolError<E>> {
-        self.connection.send(dscfg_proto::Request::Get { key: key.into() })
-            .and_then(|connection| connection.into_future().map_err(|(err, _)| err))
-            .map_err(ProtocolError::Communication)
-            .and_then(|(result, connection)| {
-                match result {
-                    Some(dscfg_proto::Response::Value { key: _, value, }) => Ok((value, Client { connection, })),
-                    None => Err(ProtocolError::UnexpectedEof),
-                    _ => Err(ProtocolError::UnexpectedResponse),
+    /// Returns future which resolves to `(Option<Val>, Self)` if successful. The value is `None`
+    /// if the key doesn't exist.
+    pub fn get_value<K: Into<String>>(self, key: K) -> impl Future<Item=(Option<Val>, Self), Error=ProtocolError<E>> {
+        self.get_value_revision(key)
+            .map(|(value, _, client)| (value, client))
+    }
+
+    /// Sends request for getting value of given key and waits for the answer.
+    ///
+    /// Returns future which resolves to `(Option<Val>, Revision, Self)` if successful. The value
+    /// is `None` if the key doesn't exist. The revision can be used with `compare_and_set()`.
+    pub fn get_value_revision<K: Into<String>>(self, key: K) -> impl Future<Item=(Option<Val>, Revision, Self), Error=ProtocolError<E>> {
+        self.request(Operation::Get { key: key.into() })
+            .and_then(|(reply, client)| {
+                let (value, revision) = message::value(reply)?;
+                Ok((value, revision, client))
+            })
+    }
+
+    /// Sends request to set the `key` to given `value` if the `expected` condition holds and waits
+    /// for the answer.
+    ///
+    /// Returns future which resolves to `(CompareAndSetResult<Val>, Self)` if successful.
+    pub fn compare_and_set<K: Into<String>>(self, key: K, expected: Expected<Val>, value: Val) -> impl Future<Item=(CompareAndSetResult<Val>, Self), Error=ProtocolError<E>> {
+        self.request(Operation::CompareAndSet { key: key.into(), expected, value, })
+            .and_then(|(reply, client)| message::compare_and_set(reply).map(|result| (result, client)))
+    }
+
+    /// Atomically updates the value of the `key` using the function `update`.
+    ///
+    /// The function is called with the current value (`None` if the key doesn't exist) and the
+    /// returned value is stored using `compare_and_set()`. If the value was changed by someone else
+    /// in the meantime, the function is called again with the new value, until the update succeeds.
+    ///
+    /// Returns future which resolves to `(Revision, Self)` where `Revision` is the revision of the
+    /// stored value.
+    pub fn update_value<K: Into<String>, F: FnMut(Option<Val>) -> Val>(self, key: K, mut update: F) -> impl Future<Item=(Revision, Self), Error=ProtocolError<E>> {
+        let key = key.into();
+
+        self.get_value_revision(key.clone())
+            .and_then(move |(value, revision, client)| {
+                future::loop_fn((client, value, revision), move |(client, value, revision)| {
+                    client
+                        .compare_and_set(key.clone(), Expected::Revision(revision), update(value))
+                        .map(|(result, client)| {
+                            match result {
+                                CompareAndSetResult::Updated(revision) => future::Loop::Break((revision, client)),
+                                CompareAndSetResult::Conflict { value, revision } => future::Loop::Continue((client, value, revision)),
+                            }
+                        })
+                })
+            })
+    }
+
+    /// Sends request to delete the `key` and waits for the answer.
+    ///
+    /// Returns future which resolves to `(bool, Self)` if successful. The `bool` is `true` if the
+    /// key existed.
+    pub fn delete_value<K: Into<String>>(self, key: K) -> impl Future<Item=(bool, Self), Error=ProtocolError<E>> {
+        self.request(Operation::Delete { key: key.into() })
+            .and_then(|(reply, client)| message::performed(reply).map(|result| (result, client)))
+    }
+
+    /// Sends request for getting values of all `keys` at once and waits for the answer.
+    ///
+    /// Returns future which resolves to `(Vec<Option<Val>>, Self)` if successful. The values are
+    /// in the same order as `keys` and those that don't exist are `None`. Requires the server to
+    /// support `get_many` capability.
+    pub fn get_values<K: Into<String>>(self, keys: Vec<K>) -> impl Future<Item=(Vec<Option<Val>>, Self), Error=ProtocolError<E>> {
+        let keys = keys.into_iter().map(Into::into).collect();
+
+        self.request(Operation::GetMany { keys })
+            .and_then(|(reply, client)| message::values(reply).map(|result| (result, client)))
+    }
+
+    /// Sends request for getting all keys starting with `prefix` along with their values and
+    /// waits for the answer.
+    ///
+    /// Returns future which resolves to `(Vec<(String, Val)>, Self)` if successful. The keys are
+    /// sorted. Requires the server to support `get_many` capability.
+    pub fn get_prefix<P: Into<String>>(self, prefix: P) -> impl Future<Item=(Vec<(String, Val)>, Self), Error=ProtocolError<E>> {
+        self.request(Operation::GetPrefix { prefix: prefix.into() })
+            .and_then(|(reply, client)| message::prefix_values(reply).map(|result| (result, client)))
+    }
+
+    /// Sends request for listing keys starting with `prefix` and waits for the answer.
+    ///
+    /// Returns future which resolves to `(Vec<String>, Self)` if successful. The keys are sorted.
+    pub fn list_keys<P: Into<String>>(self, prefix: P) -> impl Future<Item=(Vec<String>, Self), Error=ProtocolError<E>> {
+        self.request(Operation::List { prefix: prefix.into() })
+            .and_then(|(reply, client)| message::keys(reply).map(|result| (result, client)))
+    }
+
+    /// Sends request to apply all `changes` atomically if all `conditions` hold and waits for the
+    /// answer.
+    ///
+    /// Returns future which resolves to `(TransactionResult<Val>, Self)` if successful.
+    pub fn transaction(self, conditions: Vec<Condition<Val>>, changes: Vec<Change<Val>>) -> impl Future<Item=(TransactionResult<Val>, Self), Error=ProtocolError<E>> {
+        self.request(Operation::Transaction { conditions, changes, })
+            .and_then(|(reply, client)| message::transaction(reply).map(|result| (result, client)))
+    }
+
+    /// Subscribes for notifications of changes of values of keys matching the `pattern`
+    ///
+    /// The `pattern` may be a single key (`String` or `&str`) or `Pattern`, which allows
+    /// subscribing to prefixes or globs.
+    ///
+    /// The stream yields `(key, Some(value))` when the value changes and `(key, None)` when the
+    /// key is deleted. Changes made by a single transaction are yielded one after another. Use
+    /// `listen_changes()` if you need to know which changes belong together.
+    pub fn listen_notifications<P: Into<Pattern>>(self, pattern: P, notify_now: bool) -> impl Stream<Item=(String, Option<Val>), Error=ProtocolError<E>> {
+        self.listen_changes(pattern, notify_now)
+            .map(stream::iter_ok)
+            .flatten()
+    }
+
+    /// Subscribes for notifications of changes of values of keys matching the `pattern`
+    ///
+    /// This is the same as `listen_notifications()` except that all changes made by a single
+    /// transaction are yielded together.
+    pub fn listen_changes<P: Into<Pattern>>(self, pattern: P, notify_now: bool) -> impl Stream<Item=Vec<(String, Option<Val>)>, Error=ProtocolError<E>> {
+        self.subscribe(pattern.into(), notify_now)
+            .map(Client::into_changes)
+            .flatten_stream()
+    }
+
+    /// Subscribes for notifications of changes of values of keys matching any of the `patterns`
+    ///
+    /// This is the same as `listen_notifications()` except that it subscribes to all patterns at
+    /// once. If `notify_now` is `true`, the current value of each matching key is yielded once,
+    /// even if the key matches several patterns.
+    pub fn listen_notifications_many<P: Into<Pattern>>(self, patterns: Vec<P>, notify_now: bool) -> impl Stream<Item=(String, Option<Val>), Error=ProtocolError<E>> {
+        self.listen_changes_many(patterns, notify_now)
+            .map(stream::iter_ok)
+            .flatten()
+    }
+
+    /// Subscribes for notifications of changes of values of keys matching any of the `patterns`
+    ///
+    /// This is the same as `listen_notifications_many()` except that all changes made by a single
+    /// transaction are yielded together. The current values are yielded together as well if the
+    /// server supports subscribing to many patterns in one request.
+    pub fn listen_changes_many<P: Into<Pattern>>(self, patterns: Vec<P>, notify_now: bool) -> impl Stream<Item=Vec<(String, Option<Val>)>, Error=ProtocolError<E>> {
+        self.subscribe_all(patterns.into_iter().map(Into::into).collect(), notify_now)
+            .map(Client::into_changes)
+            .flatten_stream()
+    }
+
+    /// Subscribes to all `patterns` using a single request if the server supports it.
+    fn subscribe_all(self, patterns: Vec<Pattern>, notify_now: bool) -> impl Future<Item=Self, Error=ProtocolError<E>> {
+        if !self.has_capability(capability::SUBSCRIBE_MANY) {
+            let subscribed = stream::iter_ok(patterns)
+                .fold(self, move |client, pattern| client.subscribe(pattern, notify_now));
+
+            return future::Either::A(subscribed);
+        }
+
+        future::Either::B(self.request(Operation::SubscribeMany { patterns, notify_now, })
+            .and_then(|(reply, client)| message::performed(reply).map(|_| client)))
+    }
+
+    /// Sends the subscription request and waits for the reply.
+    fn subscribe(self, pattern: Pattern, notify_now: bool) -> impl Future<Item=Self, Error=ProtocolError<E>> {
+        self.request(Operation::Subscribe { pattern, notify_now, })
+            .and_then(|(reply, client)| message::performed(reply).map(|_| client))
+    }
+
+    /// Turns the client into stream of changes the client is subscribed to.
+    fn into_changes(self) -> impl Stream<Item=Vec<(String, Option<Val>)>, Error=ProtocolError<E>> {
+        stream::iter_ok(self.notifications)
+            .chain(self.connection.map_err(ProtocolError::Communication))
+            .filter_map(message::response_changes)
+    }
+
+    /// Subscribes for notifications of changes of values of keys matching any of the `patterns`
+    /// and keeps them in `Mirror`.
+    ///
+    /// Returns future which resolves to `(Mirror<Val>, Updater)` after the current values are
+    /// received. `Updater` is a future which keeps the mirror up to date, so it must be spawned.
+    /// It resolves when the server closes the connection.
+    pub fn mirror<P: Into<Pattern>>(self, patterns: Vec<P>) -> impl Future<Item=(Mirror<Val>, impl Future<Item=(), Error=ProtocolError<E>>), Error=ProtocolError<E>> where Val: Clone {
+        self.subscribe_all(patterns.into_iter().map(Into::into).collect(), true)
+            .map(|client| {
+                let mirror = Mirror::new();
+
+                // Current values are received before the replies to subscription requests.
+                let Client { connection, notifications, .. } = client;
+                for changes in notifications.into_iter().filter_map(message::response_changes) {
+                    mirror.apply(changes);
                 }
+
+                let updated = mirror.clone();
+                let updater = connection
+                    .map_err(ProtocolError::Communication)
+                    .filter_map(message::response_changes)
+                    .for_each(move |changes| {
+                        updated.apply(changes);
+                        Ok(())
+                    });
+
+                (mirror, updater)
+            })
+    }
+
+    /// Splits the client into cloneable `Handle` and `Driver` performing the communication.
+    ///
+    /// This allows many requests to be in flight at the same time and many subscriptions to share
+    /// a single connection. The `Driver` must be spawned.
+    pub fn multiplex(self) -> (Handle<Val, E>, Driver<C, Val>) where Val: Clone {
+        let Client { connection, capabilities, next_id, notifications } = self;
+        Driver::new(connection, next_id, notifications, capabilities)
+    }
+}
+
+impl<E, C: Stream<Item=Response<json::Value>, Error=E> + Sink<SinkItem=Request<json::Value>, SinkError=E>> Client<C> {
+    /// Gets the value of the `key` converted to `T`.
+    ///
+    /// Returns future which resolves to `(T, Self)` if successful.
+    pub fn get_key<T: DeserializeOwned>(self, key: &Key<T>) -> impl Future<Item=(T, Self), Error=BindingError<E>> {
+        let key = key.clone();
+
+        self.get_value(key.name().to_owned())
+            .map_err(BindingError::Protocol)
+            .and_then(move |(value, client)| {
+                key.decode(value.unwrap_or(json::Value::Null))
+                    .map(|value| (value, client))
+                    .map_err(|error| BindingError::Decode(vec![error]))
             })
     }
 
-    /// Subscribes for notifications of changes of value of specified `key`
-    pub fn listen_notifications<K: Into<String>>(self, key: K, notify_now: bool) -> impl Stream<Item=(String, Val), Error=E> {
-        self.connection
-            .send(dscfg_proto::Request::Subscribe { key: key.into(), notify_now, })
-            .and_then(|s| s.into_future().map_err(|(err, _)| err))
-            .map(|(_, s)| s)
+    /// Sets the `key` to given `value` and waits for the acknowledgement.
+    ///
+    /// Returns future which resolves to `Client`, if the server stored the value.
+    pub fn set_key<T: Serialize>(self, key: &Key<T>, value: &T) -> impl Future<Item=Self, Error=BindingError<E>> {
+        let value = match key.encode(value) {
+            Ok(value) => value,
+            Err(error) => return future::Either::A(future::err(BindingError::Decode(vec![error]))),
+        };
+
+        future::Either::B(self.set_value(key.name().to_owned(), value).map_err(BindingError::Protocol))
+    }
+
+    /// Gets values of all keys of the `binding` and creates the struct from them.
+    ///
+    /// Returns future which resolves to `(T, Self)` if successful.
+    pub fn load<T: DeserializeOwned>(self, binding: &Binding<T>) -> impl Future<Item=(T, Self), Error=BindingError<E>> {
+        let keys = binding.keys().collect::<Vec<_>>();
+        let binding = binding.clone();
+
+        // Fetching all values at once is much faster if the server supports it.
+        let loaded = if self.has_capability(capability::GET_MANY) {
+            let loaded = self
+                .get_values(keys.clone())
+                .map(move |(found, client)| {
+                    let values = keys
+                        .into_iter()
+                        .zip(found)
+                        .filter_map(|(key, value)| value.map(|value| (key, value)))
+                        .collect::<HashMap<_, _>>();
+
+                    (values, client)
+                });
+
+            future::Either::A(loaded)
+        } else {
+            let values = HashMap::with_capacity(keys.len());
+            let loaded = stream::iter_ok(keys)
+                .fold((values, self), |(mut values, client), key| {
+                    client
+                        .get_value(key.clone())
+                        .map(move |(value, client)| {
+                            if let Some(value) = value {
+                                values.insert(key, value);
+                            }
+                            (values, client)
+                        })
+                });
+
+            future::Either::B(loaded)
+        };
+
+        loaded
+            .map_err(BindingError::Protocol)
+            .and_then(move |(values, client)| {
+                binding
+                    .decode(&values)
+                    .map(|value| (value, client))
+                    .map_err(BindingError::Decode)
+            })
+    }
+
+    /// Subscribes for changes of all keys of the `binding`.
+    ///
+    /// The stream yields the struct created from the current values as soon as they are received
+    /// and then whenever any of the values changes. Invalid or missing values are reported
+    /// without ending the stream.
+    pub fn watch<T: DeserializeOwned>(self, binding: Binding<T>) -> impl Stream<Item=Result<T, Vec<KeyError>>, Error=ProtocolError<E>> {
+        let keys = binding.keys().collect::<Vec<_>>();
+        let mut values = HashMap::with_capacity(keys.len());
+        let mut received = HashSet::with_capacity(keys.len());
+        let key_count = keys.len();
+
+        self.subscribe_all(keys.into_iter().map(Pattern::Key).collect(), true)
+            .map(Client::into_changes)
             .flatten_stream()
-            .filter_map(|msg| match msg {
-                dscfg_proto::Response::Value { key, value } => Some((key, value)),
-                _ => None,
+            .filter_map(move |changes| {
+                for (key, value) in changes {
+                    match value {
+                        Some(value) => { values.insert(key.clone(), value); },
+                        None => { values.remove(&key); },
+                    }
+                    received.insert(key);
+                }
+
+                // Wait for the current values of all keys.
+                if received.len() < key_count {
+                    return None;
+                }
+
+                Some(binding.decode(&values))
             })
     }
 }
 
 /// Creates a dscfg client that encodes communication as length-delimited Json messages.
-pub fn new<Val: Serialize + for<'a> Deserialize<'a>, C: AsyncRead + AsyncWrite>(connection: C) -> Client<impl Stream<Item=dscfg_proto::Response<Val>, Error=io::Error> + Sink<SinkItem=dscfg_proto::Request<Val>, SinkError=io::Error>> {
+///
+/// The returned future performs the handshake with the server.
+pub fn new<Val: Serialize + for<'a> Deserialize<'a>, C: AsyncRead + AsyncWrite>(connection: C) -> impl Future<Item=Client<impl Stream<Item=Response<Val>, Error=io::Error> + Sink<SinkItem=Request<Val>, SinkError=io::Error>>, Error=ProtocolError<io::Error>> {
     // The deprecation message suggests to use `tokio-codec` instead,
     // which doesn't actually implement it, and depending on `tokio::codec`
     // just pulls in too many dependencies.
     #[allow(deprecated)]
     let client = tokio_io::codec::length_delimited::Builder::new()
         .native_endian()
+        .max_frame_length(dscfg_proto::MAX_MESSAGE_LEN)
         .new_framed(connection)
         .and_then(|message| serde_json::from_slice(&message).map_err(Into::into))
         .with(|message| serde_json::to_vec(&message).map_err(io::Error::from));
 
     Client::custom(client)
 }
+
+/// Connects to the dscfg server listening on TCP `address` and creates a client using `new()`.
+///
+/// Nagle's algorithm is turned off, since the messages are usually small and a reply is awaited
+/// after sending each of them. This function is available with `tcp` feature.
+#[cfg(feature = "tcp")]
+pub fn connect_tcp<Val: Serialize + for<'a> Deserialize<'a>>(address: &std::net::SocketAddr) -> impl Future<Item=Client<impl Stream<Item=Response<Val>, Error=io::Error> + Sink<SinkItem=Request<Val>, SinkError=io::Error>>, Error=ProtocolError<io::Error>> {
+    tokio_tcp::TcpStream::connect(address)
+        .and_then(|stream| stream.set_nodelay(true).map(|_| stream))
+        .map_err(ProtocolError::Communication)
+        .and_then(new)
+}
+
+/// Connects to the dscfg server listening on TCP `address` using TLS and creates a client using
+/// `new()`.
+///
+/// The server certificate must be valid for `domain`. If the server requires client certificates,
+/// the `connector` must be configured with one, see `tls::connector()`. This function is
+/// available with `tls` feature.
+#[cfg(feature = "tls")]
+pub fn connect_tls<Val: Serialize + for<'a> Deserialize<'a>>(address: &std::net::SocketAddr, domain: &str, connector: &tls::SslConnector) -> impl Future<Item=Client<impl Stream<Item=Response<Val>, Error=io::Error> + Sink<SinkItem=Request<Val>, SinkError=io::Error>>, Error=ProtocolError<io::Error>> {
+    let connector = connector.clone();
+    let domain = domain.to_owned();
+
+    tokio_tcp::TcpStream::connect(address)
+        .and_then(|stream| stream.set_nodelay(true).map(|_| stream))
+        .and_then(move |stream| tls::connect(&connector, &domain, stream))
+        .map_err(ProtocolError::Communication)
+        .and_then(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Client;
+    use dscfg_proto::{json, Request, Response, Reply, Operation};
+    use futures::{future, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    /// Connection replaying prepared responses and recording sent requests.
+    ///
+    /// If `server` is set, it creates the reply to each request and the connection never ends.
+    struct Connection {
+        responses: VecDeque<Response>,
+        server: Option<fn(&Request) -> Reply>,
+        requests: Arc<Mutex<Vec<json::Value>>>,
+    }
+
+    impl Connection {
+        fn new(responses: Vec<Response>, server: Option<fn(&Request) -> Reply>) -> (Self, Arc<Mutex<Vec<json::Value>>>) {
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let connection = Connection {
+                responses: responses.into(),
+                server,
+                requests: Arc::clone(&requests),
+            };
+
+            (connection, requests)
+        }
+    }
+
+    impl Stream for Connection {
+        type Item = Response;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            match self.responses.pop_front() {
+                Some(response) => Ok(Async::Ready(Some(response))),
+                None if self.server.is_some() => Ok(Async::NotReady),
+                None => Ok(Async::Ready(None)),
+            }
+        }
+    }
+
+    impl Sink for Connection {
+        type SinkItem = Request;
+        type SinkError = ();
+
+        fn start_send(&mut self, request: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+            if let Some(server) = self.server {
+                self.responses.push_back(Response::Reply { id: request.id, reply: server(&request) });
+            }
+            self.requests.lock().unwrap().push(json::to_value(&request.operation).unwrap());
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn welcome() -> Reply {
+        Reply::Welcome { version: dscfg_proto::PROTOCOL_VERSION, capabilities: Vec::new() }
+    }
+
+    #[test]
+    fn notification_before_reply() {
+        let responses = vec![
+            Response::Reply { id: 0, reply: welcome() },
+            Response::Notification { key: "a".to_owned(), value: json::Value::from(1) },
+            Response::Reply { id: 1, reply: Reply::Value { key: "b".to_owned(), value: json::Value::from(2), revision: 0 } },
+            Response::Reply { id: 2, reply: Reply::OperationOk },
+        ];
+        let (connection, _) = Connection::new(responses, None);
+
+        let client = Client::custom(connection).wait().unwrap();
+        let (value, client) = client.get_value("b").wait().unwrap();
+        assert_eq!(value, Some(json::Value::from(2)));
+
+        // The notification received while waiting for the reply isn't lost.
+        let notifications = client
+            .listen_notifications("a", false)
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(notifications, vec![("a".to_owned(), Some(json::Value::from(1)))]);
+    }
+
+    #[test]
+    fn dropped_subscription_is_canceled() {
+        let (connection, requests) = Connection::new(Vec::new(), Some(|request| match request.operation {
+            Operation::Hello { .. } => welcome(),
+            _ => Reply::OperationOk,
+        }));
+
+        let client = Client::custom(connection).wait().unwrap();
+        let (handle, driver) = client.multiplex();
+        let subscription = handle.subscribe("a", false);
+        let (subscription, driver) = match driver.select2(subscription).wait() {
+            Ok(future::Either::B((subscription, driver))) => (subscription, driver),
+            _ => panic!("subscribing failed"),
+        };
+
+        // No notification arrives, the driver must cancel the subscription anyway.
+        std::mem::drop(subscription);
+        std::mem::drop(handle);
+        driver.wait().unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1], json::json!({ "Subscribe": { "pattern": { "Key": "a" }, "notify_now": false } }));
+        assert_eq!(requests[2], json::json!({ "Unsubscribe": { "pattern": { "Key": "a" } } }));
+    }
+}
@@ -0,0 +1,469 @@
+//! Encoding of messages and interpretation of replies
+//!
+//! All clients understand replies the same way, so the code is shared here. The messages are
+//! encoded using `dscfg_proto::framing` and the clients only differ in how they perform I/O.
+
+use crate::{ProtocolError, CompareAndSetResult, TransactionResult, CAPABILITIES};
+use dscfg_proto::{Request, RequestId, Operation, Response, Reply, Revision, Entry, Change, ErrorCode};
+use dscfg_proto::Expected;
+use std::collections::VecDeque;
+
+/// Returns the first request which requests the capabilities of this client.
+pub(crate) fn hello<Val>() -> Operation<Val> {
+    Operation::Hello {
+        version: dscfg_proto::PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|capability| String::from(*capability)).collect(),
+    }
+}
+
+/// Returns the negotiated capabilities if the server accepted the connection.
+pub(crate) fn welcome<Val, E>(reply: Reply<Val>) -> Result<Vec<String>, ProtocolError<E>> {
+    match reply {
+        Reply::Welcome { version, capabilities } => {
+            if version == dscfg_proto::PROTOCOL_VERSION {
+                Ok(capabilities)
+            } else {
+                Err(ProtocolError::UnsupportedVersion { server_version: version })
+            }
+        },
+        Reply::UnsupportedVersion { version } => Err(ProtocolError::UnsupportedVersion { server_version: version }),
+        // The server refuses clients that failed authentication with an error.
+        reply => {
+            check(reply)?;
+            Err(ProtocolError::UnexpectedResponse)
+        },
+    }
+}
+
+/// Fails if the `operation` requires a capability that isn't in `capabilities`.
+pub(crate) fn check_capability<Val, E>(capabilities: &[String], operation: &Operation<Val>) -> Result<(), ProtocolError<E>> {
+    match operation.required_capability() {
+        Some(capability) if !capabilities.iter().any(|supported| supported == capability) => Err(ProtocolError::MissingCapability(capability)),
+        _ => Ok(()),
+    }
+}
+
+/// Turns `Error`, `OperationFailed` and `PermissionDenied` replies into the corresponding
+/// `ProtocolError` variants.
+pub(crate) fn check<Val, E>(reply: Reply<Val>) -> Result<Reply<Val>, ProtocolError<E>> {
+    match reply {
+        Reply::Error { code, message } => Err(ProtocolError::OperationFailed { code, message }),
+        Reply::OperationFailed => {
+            let message = "the server didn't describe the failure".to_owned();
+            Err(ProtocolError::OperationFailed { code: ErrorCode::Unknown, message })
+        },
+        Reply::PermissionDenied { key, permission } => Err(ProtocolError::PermissionDenied { key, permission }),
+        reply => Ok(reply),
+    }
+}
+
+/// Interprets the reply to `Set`.
+pub(crate) fn stored<Val, E>(reply: Reply<Val>) -> Result<(), ProtocolError<E>> {
+    match reply {
+        Reply::OperationOk => Ok(()),
+        _ => Err(ProtocolError::UnexpectedResponse),
+    }
+}
+
+/// Interprets the reply to operations which may be ignored, returning `true` if the operation
+/// was performed.
+///
+/// These are `Delete`, `Subscribe`, `SubscribeMany` and `Unsubscribe`.
+pub(crate) fn performed<Val, E>(reply: Reply<Val>) -> Result<bool, ProtocolError<E>> {
+    match reply {
+        Reply::OperationOk => Ok(true),
+        Reply::Ignored => Ok(false),
+        _ => Err(ProtocolError::UnexpectedResponse),
+    }
+}
+
+/// Interprets the reply to `Get`, the value is `None` if the key doesn't exist.
+pub(crate) fn value<Val, E>(reply: Reply<Val>) -> Result<(Option<Val>, Revision), ProtocolError<E>> {
+    match reply {
+        Reply::Value { key: _, value, revision, } => Ok((Some(value), revision)),
+        Reply::NotFound { key: _, revision } => Ok((None, revision)),
+        _ => Err(ProtocolError::UnexpectedResponse),
+    }
+}
+
+/// Interprets the reply to `GetMany`, absent keys are `None`.
+pub(crate) fn values<Val, E>(reply: Reply<Val>) -> Result<Vec<Option<Val>>, ProtocolError<E>> {
+    match reply {
+        Reply::Values { values } => {
+            let values = values
+                .into_iter()
+                .map(|entry| match entry {
+                    Entry::Present { value, .. } => Some(value),
+                    Entry::Absent { .. } => None,
+                })
+                .collect();
+
+            Ok(values)
+        },
+        _ => Err(ProtocolError::UnexpectedResponse),
+    }
+}
+
+/// Interprets the reply to `GetPrefix`, skipping absent keys.
+pub(crate) fn prefix_values<Val, E>(reply: Reply<Val>) -> Result<Vec<(String, Val)>, ProtocolError<E>> {
+    match reply {
+        Reply::Values { values } => {
+            let values = values
+                .into_iter()
+                .filter_map(|entry| match entry {
+                    Entry::Present { key, value, .. } => Some((key, value)),
+                    Entry::Absent { .. } => None,
+                })
+                .collect();
+
+            Ok(values)
+        },
+        _ => Err(ProtocolError::UnexpectedResponse),
+    }
+}
+
+/// Interprets the reply to `List`.
+pub(crate) fn keys<Val, E>(reply: Reply<Val>) -> Result<Vec<String>, ProtocolError<E>> {
+    match reply {
+        Reply::Keys { keys } => Ok(keys),
+        _ => Err(ProtocolError::UnexpectedResponse),
+    }
+}
+
+/// Interprets the reply to `CompareAndSet`.
+pub(crate) fn compare_and_set<Val, E>(reply: Reply<Val>) -> Result<CompareAndSetResult<Val>, ProtocolError<E>> {
+    match reply {
+        Reply::Updated { revision } => Ok(CompareAndSetResult::Updated(revision)),
+        Reply::Conflict { key: _, value, revision } => Ok(CompareAndSetResult::Conflict { value: Some(value), revision }),
+        Reply::NotFound { key: _, revision } => Ok(CompareAndSetResult::Conflict { value: None, revision }),
+        _ => Err(ProtocolError::UnexpectedResponse),
+    }
+}
+
+/// Interprets the reply to `Transaction`.
+pub(crate) fn transaction<Val, E>(reply: Reply<Val>) -> Result<TransactionResult<Val>, ProtocolError<E>> {
+    match reply {
+        Reply::Updated { revision } => Ok(TransactionResult::Committed(revision)),
+        Reply::Conflict { key, value, revision } => Ok(TransactionResult::Conflict { key, value: Some(value), revision }),
+        Reply::NotFound { key, revision } => Ok(TransactionResult::Conflict { key, value: None, revision }),
+        _ => Err(ProtocolError::UnexpectedResponse),
+    }
+}
+
+/// Converts notification to the list of changes.
+///
+/// Returns `None` if the response isn't a notification.
+pub(crate) fn notification_changes<Val>(response: Response<Val>) -> Option<Vec<Change<Val>>> {
+    match response {
+        Response::Notification { key, value } => Some(vec![Change::Set { key, value }]),
+        Response::Deleted { key } => Some(vec![Change::Delete { key }]),
+        Response::Changes { changes } => Some(changes),
+        Response::Reply { .. } => None,
+    }
+}
+
+/// Converts notification to the list of changed keys with new values, `None` if the key was
+/// deleted.
+///
+/// Returns `None` if the response isn't a notification.
+pub(crate) fn response_changes<Val>(response: Response<Val>) -> Option<Vec<(String, Option<Val>)>> {
+    let changes = notification_changes(response)?
+        .into_iter()
+        .map(|change| match change {
+            Change::Set { key, value } => (key, Some(value)),
+            Change::Delete { key } => (key, None),
+        })
+        .collect();
+
+    Some(changes)
+}
+
+/// State of a connection independent of how the messages are sent and received.
+///
+/// The clients write the requests returned by `request()` and pass the received responses to
+/// `reply()` until it returns the awaited reply.
+pub(crate) struct Session<Val> {
+    capabilities: Vec<String>,
+    next_id: RequestId,
+    // Notifications received while waiting for a reply.
+    notifications: VecDeque<Vec<Change<Val>>>,
+}
+
+impl<Val> Session<Val> {
+    /// Creates a session which didn't perform the handshake yet.
+    pub(crate) fn new() -> Self {
+        Session {
+            capabilities: Vec::new(),
+            next_id: 0,
+            notifications: VecDeque::new(),
+        }
+    }
+
+    /// Stores the capabilities negotiated by the reply to `hello()`.
+    pub(crate) fn welcome<E>(&mut self, reply: Reply<Val>) -> Result<(), ProtocolError<E>> {
+        self.capabilities = welcome(reply)?;
+        Ok(())
+    }
+
+    /// Returns `true` if the capability was negotiated with the server.
+    pub(crate) fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|supported| supported == capability)
+    }
+
+    /// Assigns an id to the `operation`.
+    ///
+    /// Fails if the operation requires a capability that wasn't negotiated.
+    pub(crate) fn request<E>(&mut self, operation: Operation<Val>) -> Result<Request<Val>, ProtocolError<E>> {
+        check_capability(&self.capabilities, &operation)?;
+
+        Ok(self.request_unchecked(operation))
+    }
+
+    /// Assigns an id to the `operation` without checking the capabilities.
+    pub(crate) fn request_unchecked(&mut self, operation: Operation<Val>) -> Request<Val> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        Request { id, operation }
+    }
+
+    /// Returns the reply if the `response` is the reply to the request with given `id`.
+    ///
+    /// Replies to other requests are skipped and notifications are buffered.
+    pub(crate) fn reply(&mut self, id: RequestId, response: Response<Val>) -> Option<Reply<Val>> {
+        match response {
+            Response::Reply { id: reply_id, reply } => if reply_id == id {
+                return Some(reply);
+            },
+            notification => self.notifications.extend(notification_changes(notification)),
+        }
+
+        None
+    }
+
+    /// Returns the changes buffered while waiting for a reply.
+    pub(crate) fn buffered_changes(&mut self) -> Option<Vec<Change<Val>>> {
+        self.notifications.pop_front()
+    }
+}
+
+/// Operation along with the interpretation of the reply to it.
+pub(crate) struct Call<Val, T, E> {
+    pub(crate) operation: Operation<Val>,
+    pub(crate) interpret: fn(Reply<Val>) -> Result<T, ProtocolError<E>>,
+}
+
+impl<Val, T, E> Call<Val, T, E> {
+    fn new(operation: Operation<Val>, interpret: fn(Reply<Val>) -> Result<T, ProtocolError<E>>) -> Self {
+        Call { operation, interpret }
+    }
+}
+
+/// Constructors of calls performed by the clients.
+pub(crate) mod call {
+    use crate::{CompareAndSetResult, TransactionResult};
+    use dscfg_proto::{Operation, Revision, Pattern, Expected, Condition, Change};
+    use super::Call;
+
+    pub(crate) fn set<Val, E>(key: String, value: Val) -> Call<Val, (), E> {
+        Call::new(Operation::Set { key, value, }, super::stored)
+    }
+
+    pub(crate) fn get<Val, E>(key: String) -> Call<Val, (Option<Val>, Revision), E> {
+        Call::new(Operation::Get { key }, super::value)
+    }
+
+    pub(crate) fn get_many<Val, E>(keys: Vec<String>) -> Call<Val, Vec<Option<Val>>, E> {
+        Call::new(Operation::GetMany { keys }, super::values)
+    }
+
+    pub(crate) fn get_prefix<Val, E>(prefix: String) -> Call<Val, Vec<(String, Val)>, E> {
+        Call::new(Operation::GetPrefix { prefix }, super::prefix_values)
+    }
+
+    pub(crate) fn compare_and_set<Val, E>(key: String, expected: Expected<Val>, value: Val) -> Call<Val, CompareAndSetResult<Val>, E> {
+        Call::new(Operation::CompareAndSet { key, expected, value, }, super::compare_and_set)
+    }
+
+    pub(crate) fn delete<Val, E>(key: String) -> Call<Val, bool, E> {
+        Call::new(Operation::Delete { key }, super::performed)
+    }
+
+    pub(crate) fn list<Val, E>(prefix: String) -> Call<Val, Vec<String>, E> {
+        Call::new(Operation::List { prefix }, super::keys)
+    }
+
+    pub(crate) fn transaction<Val, E>(conditions: Vec<Condition<Val>>, changes: Vec<Change<Val>>) -> Call<Val, TransactionResult<Val>, E> {
+        Call::new(Operation::Transaction { conditions, changes, }, super::transaction)
+    }
+
+    pub(crate) fn subscribe<Val, E>(pattern: Pattern, notify_now: bool) -> Call<Val, bool, E> {
+        Call::new(Operation::Subscribe { pattern, notify_now, }, super::performed)
+    }
+
+    pub(crate) fn subscribe_many<Val, E>(patterns: Vec<Pattern>, notify_now: bool) -> Call<Val, bool, E> {
+        Call::new(Operation::SubscribeMany { patterns, notify_now, }, super::performed)
+    }
+
+    pub(crate) fn unsubscribe<Val, E>(pattern: Pattern) -> Call<Val, bool, E> {
+        Call::new(Operation::Unsubscribe { pattern }, super::performed)
+    }
+}
+
+/// Next step of updating a value using compare-and-set.
+pub(crate) enum UpdateStep<Val, E> {
+    /// The value has to be stored using the call.
+    Attempt(Call<Val, CompareAndSetResult<Val>, E>),
+    /// The value was stored with the revision.
+    Done(Revision),
+}
+
+/// Returns the next step of `update()` of the clients after the previous compare-and-set
+/// finished with `result`.
+///
+/// The clients start with the current value and revision as if they were a conflict and perform
+/// the attempts until the update is done.
+pub(crate) fn update_step<Val, E, F: FnMut(Option<Val>) -> Val>(key: &str, result: CompareAndSetResult<Val>, update: &mut F) -> UpdateStep<Val, E> {
+    match result {
+        CompareAndSetResult::Updated(revision) => UpdateStep::Done(revision),
+        CompareAndSetResult::Conflict { value, revision } => {
+            UpdateStep::Attempt(call::compare_and_set(key.to_owned(), Expected::Revision(revision), update(value)))
+        },
+    }
+}
+
+/// In-memory server for testing the clients.
+#[cfg(test)]
+pub(crate) mod loopback {
+    use dscfg_proto::{json, framing, Request, Response, Operation, Reply, Expected, Revision, ErrorCode};
+    use std::collections::HashMap;
+    use std::io::{self, Read, Write};
+
+    /// Stream connected to a server keeping the values in memory.
+    ///
+    /// The requests are handled as soon as they are written, so the replies can be read
+    /// immediately. Only operations needed by the tests are supported.
+    #[derive(Default)]
+    pub(crate) struct Loopback {
+        values: HashMap<String, (json::Value, Revision)>,
+        last_revision: Revision,
+        /// Value stored by another client right before the next compare-and-set.
+        pub(crate) concurrent_set: Option<(String, json::Value)>,
+        input: Vec<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Loopback {
+        fn store(&mut self, key: String, value: json::Value) -> Revision {
+            self.last_revision += 1;
+            self.values.insert(key, (value, self.last_revision));
+            self.last_revision
+        }
+
+        fn handle(&mut self, operation: Operation) -> Reply {
+            match operation {
+                Operation::Hello { capabilities, .. } => Reply::Welcome { version: dscfg_proto::PROTOCOL_VERSION, capabilities },
+                Operation::Set { key, value } => {
+                    self.store(key, value);
+                    Reply::OperationOk
+                },
+                Operation::Get { key } => match self.values.get(&key) {
+                    Some((value, revision)) => Reply::Value { key, value: value.clone(), revision: *revision },
+                    None => Reply::NotFound { key, revision: 0 },
+                },
+                Operation::CompareAndSet { key, expected, value } => {
+                    if let Some((key, value)) = self.concurrent_set.take() {
+                        self.store(key, value);
+                    }
+
+                    let matches = match (expected, self.values.get(&key)) {
+                        (Expected::Revision(expected), Some((_, revision))) => expected == *revision,
+                        (Expected::Revision(expected), None) => expected == 0,
+                        (Expected::Value(expected), Some((value, _))) => expected == *value,
+                        (Expected::Absent, None) => true,
+                        _ => false,
+                    };
+
+                    if matches {
+                        Reply::Updated { revision: self.store(key, value) }
+                    } else {
+                        match self.values.get(&key) {
+                            Some((value, revision)) => Reply::Conflict { value: value.clone(), revision: *revision, key },
+                            None => Reply::NotFound { key, revision: 0 },
+                        }
+                    }
+                },
+                _ => Reply::Error { code: ErrorCode::Unsupported, message: "unsupported operation".to_owned() },
+            }
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.input.extend_from_slice(buf);
+
+            while let Some(request) = framing::decode_from::<Request>(&mut self.input)? {
+                let reply = self.handle(request.operation);
+                framing::encode_into(&Response::Reply { id: request.id, reply }, &mut self.output)?;
+            }
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = (&self.output[..]).read(buf)?;
+            self.output.drain(..read);
+            Ok(read)
+        }
+    }
+
+    #[cfg(feature = "asynchronous")]
+    mod asynchronous {
+        use super::Loopback;
+        use futures03::io::{AsyncRead, AsyncWrite};
+        use futures03::task::{Context, Poll};
+        use std::io::{self, Read, Write};
+        use std::pin::Pin;
+
+        impl AsyncRead for Loopback {
+            fn poll_read(self: Pin<&mut Self>, _context: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                Poll::Ready(self.get_mut().read(buf))
+            }
+        }
+
+        impl AsyncWrite for Loopback {
+            fn poll_write(self: Pin<&mut Self>, _context: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+                Poll::Ready(self.get_mut().write(buf))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _context: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _context: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::value;
+    use dscfg_proto::{json, framing, Reply};
+
+    #[test]
+    fn value_without_revision() {
+        let reply = framing::decode::<Reply>(br#"{"Value":{"key":"a","value":1}}"#).unwrap();
+
+        let (value, revision) = value::<_, ()>(reply).unwrap();
+        assert_eq!(value, Some(json::Value::from(1)));
+        assert_eq!(revision, 0);
+    }
+}
@@ -0,0 +1,160 @@
+//! Local copy of configuration
+//!
+//! `Mirror` keeps values of subscribed keys in memory and updates them whenever a notification
+//! arrives, so reading them doesn't need any communication with the server. It can be created
+//! using `Client::mirror()`.
+
+use crate::{json, Key, KeyError};
+use futures::sync::mpsc;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+type Callback<Val> = Arc<dyn Fn(&str, Option<&Val>) + Send + Sync>;
+type Watcher<Val> = mpsc::UnboundedSender<(String, Option<Val>)>;
+
+/// In-memory copy of values of subscribed keys
+///
+/// The mirror is kept up to date by the future returned along with it from `Client::mirror()`,
+/// which must be running. Reading values only needs a read lock, so it's cheap.
+///
+/// The mirror can be cloned cheaply - the clones share the values.
+pub struct Mirror<Val = json::Value> {
+    shared: Arc<Shared<Val>>,
+}
+
+struct Shared<Val> {
+    values: RwLock<HashMap<String, Val>>,
+    callbacks: Mutex<Vec<Callback<Val>>>,
+    watchers: Mutex<Vec<Watcher<Val>>>,
+}
+
+impl<Val: Clone> Mirror<Val> {
+    pub(crate) fn new() -> Self {
+        let shared = Shared {
+            values: RwLock::new(HashMap::new()),
+            callbacks: Mutex::new(Vec::new()),
+            watchers: Mutex::new(Vec::new()),
+        };
+
+        Mirror {
+            shared: Arc::new(shared),
+        }
+    }
+
+    /// Returns the current value of the `key` or `None` if the key doesn't exist or isn't mirrored.
+    pub fn get(&self, key: &str) -> Option<Val> {
+        self.read(key, |value| value.cloned())
+    }
+
+    /// Calls `f` with the current value of the `key` without cloning it.
+    ///
+    /// The values can't change while `f` is running, so it should be short.
+    pub fn read<R, F: FnOnce(Option<&Val>) -> R>(&self, key: &str, f: F) -> R {
+        let values = self.shared.values.read().unwrap();
+        f(values.get(key))
+    }
+
+    /// Returns `true` if the key exists and is mirrored.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.read(key, |value| value.is_some())
+    }
+
+    /// Returns copy of all mirrored values.
+    pub fn snapshot(&self) -> HashMap<String, Val> {
+        self.shared.values.read().unwrap().clone()
+    }
+
+    /// Registers a function which is called whenever a mirrored value changes.
+    ///
+    /// The function is called with the changed key and `Some(value)` if the value was set or
+    /// `None` if it was deleted. It runs in the task updating the mirror, so it shouldn't block.
+    pub fn on_change<F: 'static + Fn(&str, Option<&Val>) + Send + Sync>(&self, callback: F) {
+        self.shared.callbacks.lock().unwrap().push(Arc::new(callback));
+    }
+
+    /// Returns stream of changes of mirrored values.
+    ///
+    /// The stream yields `(key, Some(value))` when the value changes and `(key, None)` when the
+    /// key is deleted. Only changes that happen after calling this method are yielded.
+    pub fn watch(&self) -> mpsc::UnboundedReceiver<(String, Option<Val>)> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.shared.watchers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Applies the changes received from the server.
+    pub(crate) fn apply(&self, changes: Vec<(String, Option<Val>)>) {
+        {
+            let mut values = self.shared.values.write().unwrap();
+            for (key, value) in &changes {
+                match value {
+                    Some(value) => { values.insert(key.clone(), value.clone()); },
+                    None => { values.remove(key); },
+                }
+            }
+        }
+
+        // Called without holding the locks, so that the callbacks can read the mirror and register
+        // other callbacks.
+        let callbacks = self.shared.callbacks.lock().unwrap().clone();
+        for (key, value) in &changes {
+            for callback in &callbacks {
+                callback(key, value.as_ref());
+            }
+        }
+
+        let mut watchers = self.shared.watchers.lock().unwrap();
+        watchers.retain(|watcher| {
+            changes
+                .iter()
+                .all(|change| watcher.unbounded_send(change.clone()).is_ok())
+        });
+    }
+}
+
+impl Mirror<json::Value> {
+    /// Returns the current value of the `key` converted to `T`.
+    ///
+    /// Keys that don't exist or aren't mirrored are considered missing.
+    pub fn get_key<T: DeserializeOwned>(&self, key: &Key<T>) -> Result<T, KeyError> {
+        let value = self.get(key.name()).unwrap_or(json::Value::Null);
+        key.decode(value)
+    }
+}
+
+impl<Val> Clone for Mirror<Val> {
+    fn clone(&self) -> Self {
+        Mirror {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mirror;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn callback_may_use_mirror() {
+        let mirror = Mirror::<u32>::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let registered = mirror.clone();
+        let counter = Arc::clone(&calls);
+        mirror.on_change(move |key, value| {
+            assert_eq!(registered.get(key), value.cloned());
+            let counter = Arc::clone(&counter);
+            registered.on_change(move |_, _| { counter.fetch_add(1, Ordering::SeqCst); });
+        });
+
+        mirror.apply(vec![("a".to_owned(), Some(1))]);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        mirror.apply(vec![("a".to_owned(), None)]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(mirror.get("a"), None);
+    }
+}
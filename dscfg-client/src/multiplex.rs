@@ -0,0 +1,412 @@
+//! Sharing one connection by many tasks
+//!
+//! Methods of `Client` consume it and wait for a single reply at a time. `Client::multiplex()`
+//! splits the client into a cloneable `Handle`, which can send many requests concurrently, and
+//! `Driver`, which is a future that must be spawned to perform the communication.
+//!
+//! The driver sends requests as soon as they are made, matches replies to them using request ids
+//! and routes notifications to `Subscription` streams with matching patterns.
+
+use crate::{message, ProtocolError, CompareAndSetResult, TransactionResult};
+use dscfg_proto::{Request, RequestId, Operation, Response, Reply, Pattern, Expected, Revision, Condition, Change};
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+type Changes<Val> = Vec<(String, Option<Val>)>;
+
+enum Command<Val> {
+    Request {
+        operation: Operation<Val>,
+        reply: oneshot::Sender<Reply<Val>>,
+    },
+    Subscribe {
+        pattern: Pattern,
+        notify_now: bool,
+        reply: oneshot::Sender<Result<mpsc::UnboundedReceiver<Changes<Val>>, Reply<Val>>>,
+    },
+    // A subscription with the pattern was dropped.
+    Dropped {
+        pattern: Pattern,
+    },
+}
+
+/// Request waiting for reply.
+enum Pending<Val> {
+    Request(oneshot::Sender<Reply<Val>>),
+    Subscribe {
+        pattern: Pattern,
+        receiver: mpsc::UnboundedReceiver<Changes<Val>>,
+        reply: oneshot::Sender<Result<mpsc::UnboundedReceiver<Changes<Val>>, Reply<Val>>>,
+    },
+    // Nobody is interested in the reply.
+    Ignored,
+}
+
+/// Cloneable handle for making requests over shared connection.
+///
+/// All methods take `&self` and the returned futures may run concurrently. They fail with
+/// `ProtocolError::UnexpectedEof` if the `Driver` stopped.
+pub struct Handle<Val, E> {
+    commands: mpsc::UnboundedSender<Command<Val>>,
+    capabilities: Arc<Vec<String>>,
+    _phantom: PhantomData<fn() -> E>,
+}
+
+impl<Val, E> Clone for Handle<Val, E> {
+    fn clone(&self) -> Self {
+        Handle {
+            commands: self.commands.clone(),
+            capabilities: Arc::clone(&self.capabilities),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<Val, E> Handle<Val, E> {
+    /// Returns `true` if the capability was negotiated with the server.
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|supported| supported == capability)
+    }
+
+    /// Sends the request and waits for the reply.
+    ///
+    /// Fails without sending anything if the operation requires a capability that wasn't
+    /// negotiated. `Error` and `PermissionDenied` replies are turned into the corresponding
+    /// `ProtocolError` variants.
+    fn request(&self, operation: Operation<Val>) -> impl Future<Item=Reply<Val>, Error=ProtocolError<E>> {
+        if let Err(err) = message::check_capability(&self.capabilities, &operation) {
+            return future::Either::A(future::err(err));
+        }
+
+        let (reply, receiver) = oneshot::channel();
+        // If the driver stopped, the reply sender is dropped, which is handled below.
+        let _ = self.commands.unbounded_send(Command::Request { operation, reply });
+
+        future::Either::B(receiver
+            .map_err(|_| ProtocolError::UnexpectedEof)
+            .and_then(message::check))
+    }
+
+    /// Sets the `key` to given `value` and waits for the acknowledgement.
+    pub fn set_value<K: Into<String>>(&self, key: K, value: Val) -> impl Future<Item=(), Error=ProtocolError<E>> {
+        self.request(Operation::Set { key: key.into(), value, })
+            .and_then(message::stored)
+    }
+
+    /// Gets the value of the `key` or `None` if it doesn't exist.
+    pub fn get_value<K: Into<String>>(&self, key: K) -> impl Future<Item=Option<Val>, Error=ProtocolError<E>> {
+        self.get_value_revision(key).map(|(value, _)| value)
+    }
+
+    /// Gets the value of the `key` along with its revision.
+    pub fn get_value_revision<K: Into<String>>(&self, key: K) -> impl Future<Item=(Option<Val>, Revision), Error=ProtocolError<E>> {
+        self.request(Operation::Get { key: key.into() })
+            .and_then(message::value)
+    }
+
+    /// Gets the values of all `keys` at once.
+    ///
+    /// The values are in the same order as `keys` and those that don't exist are `None`.
+    /// Requires `get_many` capability.
+    pub fn get_values<K: Into<String>>(&self, keys: Vec<K>) -> impl Future<Item=Vec<Option<Val>>, Error=ProtocolError<E>> {
+        let keys = keys.into_iter().map(Into::into).collect();
+
+        self.request(Operation::GetMany { keys })
+            .and_then(message::values)
+    }
+
+    /// Gets all keys starting with `prefix` along with their values.
+    ///
+    /// The keys are sorted. Requires `get_many` capability.
+    pub fn get_prefix<P: Into<String>>(&self, prefix: P) -> impl Future<Item=Vec<(String, Val)>, Error=ProtocolError<E>> {
+        self.request(Operation::GetPrefix { prefix: prefix.into() })
+            .and_then(message::prefix_values)
+    }
+
+    /// Sets the `key` to given `value` if the `expected` condition holds.
+    pub fn compare_and_set<K: Into<String>>(&self, key: K, expected: Expected<Val>, value: Val) -> impl Future<Item=CompareAndSetResult<Val>, Error=ProtocolError<E>> {
+        self.request(Operation::CompareAndSet { key: key.into(), expected, value, })
+            .and_then(message::compare_and_set)
+    }
+
+    /// Deletes the `key`.
+    ///
+    /// Resolves to `true` if the key existed.
+    pub fn delete_value<K: Into<String>>(&self, key: K) -> impl Future<Item=bool, Error=ProtocolError<E>> {
+        self.request(Operation::Delete { key: key.into() })
+            .and_then(message::performed)
+    }
+
+    /// Lists keys starting with `prefix`.
+    ///
+    /// The keys are sorted.
+    pub fn list_keys<P: Into<String>>(&self, prefix: P) -> impl Future<Item=Vec<String>, Error=ProtocolError<E>> {
+        self.request(Operation::List { prefix: prefix.into() })
+            .and_then(message::keys)
+    }
+
+    /// Applies all `changes` atomically if all `conditions` hold.
+    pub fn transaction(&self, conditions: Vec<Condition<Val>>, changes: Vec<Change<Val>>) -> impl Future<Item=TransactionResult<Val>, Error=ProtocolError<E>> {
+        self.request(Operation::Transaction { conditions, changes, })
+            .and_then(message::transaction)
+    }
+
+    /// Subscribes for notifications of changes of values of keys matching the `pattern`.
+    ///
+    /// Resolves to `Subscription` after the server confirms the subscription. If `notify_now` is
+    /// `true`, current values are yielded first. Other subscriptions with overlapping patterns may
+    /// receive the current values again in such case.
+    ///
+    /// The server is told to cancel the subscription after all subscriptions with the same
+    /// pattern are dropped.
+    pub fn subscribe<P: Into<Pattern>>(&self, pattern: P, notify_now: bool) -> impl Future<Item=Subscription<Val, E>, Error=ProtocolError<E>> {
+        let pattern = pattern.into();
+        let commands = self.commands.clone();
+        let (reply, receiver) = oneshot::channel();
+        let _ = self.commands.unbounded_send(Command::Subscribe { pattern: pattern.clone(), notify_now, reply });
+
+        receiver
+            .map_err(|_| ProtocolError::UnexpectedEof)
+            .and_then(move |result| match result {
+                Ok(receiver) => Ok(Subscription { receiver, pattern, commands, _phantom: PhantomData }),
+                // Errors are reported as such, any other reply is unexpected.
+                Err(reply) => message::check(reply).and(Err(ProtocolError::UnexpectedResponse)),
+            })
+    }
+}
+
+/// Stream of changes of values of keys matching the pattern of the subscription.
+///
+/// All changes made by a single transaction are yielded together. The stream ends when the
+/// `Driver` stops.
+pub struct Subscription<Val, E> {
+    receiver: mpsc::UnboundedReceiver<Changes<Val>>,
+    pattern: Pattern,
+    // Used to tell the driver that the subscription was dropped.
+    commands: mpsc::UnboundedSender<Command<Val>>,
+    _phantom: PhantomData<fn() -> E>,
+}
+
+impl<Val, E> Stream for Subscription<Val, E> {
+    type Item = Changes<Val>;
+    type Error = ProtocolError<E>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // Receiving from the channel never fails.
+        Ok(self.receiver.poll().unwrap_or(Async::Ready(None)))
+    }
+}
+
+impl<Val, E> Drop for Subscription<Val, E> {
+    fn drop(&mut self) {
+        // Closed before notifying the driver, so that it sees the subscription as closed.
+        self.receiver.close();
+        let pattern = std::mem::replace(&mut self.pattern, Pattern::Key(String::new()));
+        let _ = self.commands.unbounded_send(Command::Dropped { pattern });
+    }
+}
+
+/// Future performing the communication for `Handle`.
+///
+/// It resolves after all handles are dropped and all requests and subscriptions are finished. It
+/// fails if the connection fails.
+pub struct Driver<C: Stream, Val> {
+    connection: C,
+    commands: mpsc::UnboundedReceiver<Command<Val>>,
+    commands_finished: bool,
+    next_id: RequestId,
+    // Requests not yet accepted by the connection.
+    outgoing: VecDeque<Request<Val>>,
+    // Responses received before the driver was created.
+    received: VecDeque<Response<Val>>,
+    pending: HashMap<RequestId, Pending<Val>>,
+    subscriptions: HashMap<Pattern, Vec<mpsc::UnboundedSender<Changes<Val>>>>,
+}
+
+impl<Val: Clone, E, C: Stream<Item=Response<Val>, Error=E> + Sink<SinkItem=Request<Val>, SinkError=E>> Driver<C, Val> {
+    pub(crate) fn new(connection: C, next_id: RequestId, received: VecDeque<Response<Val>>, capabilities: Vec<String>) -> (Handle<Val, E>, Self) {
+        let (sender, commands) = mpsc::unbounded();
+
+        let handle = Handle {
+            commands: sender,
+            capabilities: Arc::new(capabilities),
+            _phantom: PhantomData,
+        };
+
+        let driver = Driver {
+            connection,
+            commands,
+            commands_finished: false,
+            next_id,
+            outgoing: VecDeque::new(),
+            received,
+            pending: HashMap::new(),
+            subscriptions: HashMap::new(),
+        };
+
+        (handle, driver)
+    }
+
+    fn send(&mut self, operation: Operation<Val>, pending: Pending<Val>) {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        self.outgoing.push_back(Request { id, operation });
+        self.pending.insert(id, pending);
+    }
+
+    fn start(&mut self, command: Command<Val>) {
+        match command {
+            Command::Request { operation, reply } => self.send(operation, Pending::Request(reply)),
+            Command::Subscribe { pattern, notify_now, reply } => {
+                // Registered immediately, because current values arrive before the reply.
+                let (sender, receiver) = mpsc::unbounded();
+                self.subscriptions.entry(pattern.clone()).or_default().push(sender);
+
+                let operation = Operation::Subscribe { pattern: pattern.clone(), notify_now, };
+                self.send(operation, Pending::Subscribe { pattern, receiver, reply });
+            },
+            Command::Dropped { pattern } => {
+                if self.remove_closed(&pattern) {
+                    self.send(Operation::Unsubscribe { pattern }, Pending::Ignored);
+                }
+            },
+        }
+    }
+
+    fn handle_response(&mut self, response: Response<Val>) {
+        let (id, reply) = match response {
+            Response::Reply { id, reply } => (id, reply),
+            notification => {
+                if let Some(changes) = message::response_changes(notification) {
+                    self.route(changes);
+                }
+                return;
+            },
+        };
+
+        match self.pending.remove(&id) {
+            Some(Pending::Request(sender)) => { let _ = sender.send(reply); },
+            Some(Pending::Subscribe { pattern, receiver, reply: sender }) => match reply {
+                Reply::OperationOk | Reply::Ignored => { let _ = sender.send(Ok(receiver)); },
+                reply => {
+                    std::mem::drop(receiver);
+                    self.remove_closed(&pattern);
+                    let _ = sender.send(Err(reply));
+                },
+            },
+            Some(Pending::Ignored) | None => (),
+        }
+    }
+
+    /// Sends the changes to the subscriptions with matching patterns.
+    fn route(&mut self, changes: Changes<Val>) {
+        let mut closed = Vec::new();
+
+        for (pattern, subscribers) in &self.subscriptions {
+            let matching = changes
+                .iter()
+                .filter(|(key, _)| pattern.matches(key))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if matching.is_empty() {
+                continue;
+            }
+
+            for subscriber in subscribers {
+                if subscriber.unbounded_send(matching.clone()).is_err() {
+                    closed.push(pattern.clone());
+                }
+            }
+        }
+
+        for pattern in closed {
+            if self.remove_closed(&pattern) {
+                self.send(Operation::Unsubscribe { pattern }, Pending::Ignored);
+            }
+        }
+    }
+
+    /// Removes dropped subscriptions.
+    ///
+    /// Returns `true` if there are no more subscriptions with the `pattern`.
+    fn remove_closed(&mut self, pattern: &Pattern) -> bool {
+        let is_empty = match self.subscriptions.get_mut(pattern) {
+            Some(subscribers) => {
+                subscribers.retain(|subscriber| !subscriber.is_closed());
+                subscribers.is_empty()
+            },
+            None => false,
+        };
+
+        if is_empty {
+            self.subscriptions.remove(pattern);
+        }
+
+        is_empty
+    }
+
+    fn is_finished(&self) -> bool {
+        self.commands_finished && self.pending.is_empty() && self.subscriptions.is_empty() && self.outgoing.is_empty()
+    }
+}
+
+impl<Val: Clone, E, C: Stream<Item=Response<Val>, Error=E> + Sink<SinkItem=Request<Val>, SinkError=E>> Future for Driver<C, Val> {
+    type Item = ();
+    type Error = ProtocolError<E>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        while let Some(response) = self.received.pop_front() {
+            self.handle_response(response);
+        }
+
+        // Handling responses may create new requests, so this repeats until there's nothing to
+        // send or the connection can't accept more. All polled objects notify the task when they
+        // become ready.
+        loop {
+            while !self.commands_finished {
+                match self.commands.poll() {
+                    Ok(Async::Ready(Some(command))) => self.start(command),
+                    Ok(Async::Ready(None)) | Err(()) => self.commands_finished = true,
+                    Ok(Async::NotReady) => break,
+                }
+            }
+
+            while let Some(request) = self.outgoing.pop_front() {
+                match self.connection.start_send(request).map_err(ProtocolError::Communication)? {
+                    AsyncSink::Ready => (),
+                    AsyncSink::NotReady(request) => {
+                        self.outgoing.push_front(request);
+                        break;
+                    },
+                }
+            }
+            self.connection.poll_complete().map_err(ProtocolError::Communication)?;
+            let sink_full = !self.outgoing.is_empty();
+
+            loop {
+                match self.connection.poll().map_err(ProtocolError::Communication)? {
+                    Async::Ready(Some(response)) => self.handle_response(response),
+                    Async::Ready(None) => return if self.is_finished() {
+                        Ok(Async::Ready(()))
+                    } else {
+                        Err(ProtocolError::UnexpectedEof)
+                    },
+                    Async::NotReady => break,
+                }
+            }
+
+            if self.is_finished() {
+                return Ok(Async::Ready(()));
+            }
+
+            if sink_full || self.outgoing.is_empty() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
@@ -0,0 +1,350 @@
+//! Subscriptions surviving reconnection
+//!
+//! The stream returned by `Client::listen_changes()` ends when the connection to the server is
+//! lost, e.g. because the server restarted. `Reconnecting` connects again in such case, restores
+//! the subscriptions and reports changes that happened while it was disconnected.
+//!
+//! This module is available with `reconnect` feature and requires `tokio` timer to be running.
+
+use crate::{message, Client, ProtocolError, Pattern};
+use dscfg_proto::{Request, Response};
+use futures::{Async, Future, IntoFuture, Poll, Sink, Stream};
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+/// Specifies how long to wait between attempts to connect.
+///
+/// The delay starts at `initial` and is multiplied by `multiplier` after each failed attempt, but
+/// it never exceeds `max`. It's reset after the client successfully connects.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Delay after the first failure.
+    pub initial: Duration,
+    /// Maximum delay.
+    pub max: Duration,
+    /// How many times the delay increases after each failure.
+    pub multiplier: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2,
+        }
+    }
+}
+
+/// Event produced by `Reconnecting`.
+#[derive(Debug)]
+pub enum Event<Val, E> {
+    /// The client connected and subscribed.
+    ///
+    /// This event is followed by `Changes` containing values that changed since the last time
+    /// the client was connected (all current values if it's the first connection).
+    Connected,
+    /// The connection was lost or the attempt to connect failed.
+    ///
+    /// Another attempt will be made after `retry_in`.
+    Disconnected { error: ProtocolError<E>, retry_in: Duration },
+    /// Values of subscribed keys changed.
+    ///
+    /// Contains `(key, Some(value))` for each changed value and `(key, None)` for each deleted key.
+    Changes(Vec<(String, Option<Val>)>),
+}
+
+type Subscribing<C, E> = Box<dyn Future<Item=Client<C>, Error=ProtocolError<E>> + Send>;
+
+/// Progress made while connecting.
+enum Step<C: Stream> {
+    Connected(Client<C>),
+    Subscribed(Client<C>),
+}
+
+enum State<C: Stream, Fut, E> {
+    Connecting(Fut),
+    Subscribing(Subscribing<C, E>),
+    Connected(C),
+    Waiting(Delay),
+}
+
+/// Stream of changes of subscribed keys which reconnects when the connection is lost.
+///
+/// The stream also reports changes of the state of the connection. It never ends, but fails if
+/// the timer fails.
+pub struct Reconnecting<F, Fut: IntoFuture, C: Stream, Val, E> {
+    connect: F,
+    patterns: Vec<Pattern>,
+    backoff: Backoff,
+    delay: Duration,
+    state: State<C, Fut::Future, E>,
+    // Last known values of subscribed keys, used to find out what changed while disconnected.
+    values: HashMap<String, Val>,
+    events: VecDeque<Event<Val, E>>,
+}
+
+impl<F, Fut, C, Val, E> Reconnecting<F, Fut, C, Val, E> where
+    F: FnMut() -> Fut,
+    Fut: IntoFuture<Item=Client<C>, Error=ProtocolError<E>>,
+    C: 'static + Stream<Item=Response<Val>, Error=E> + Sink<SinkItem=Request<Val>, SinkError=E> + Send,
+    Val: 'static + Serialize + for<'a> Deserialize<'a> + Clone + PartialEq + Send,
+    E: 'static + Send {
+
+    /// Creates the stream which subscribes to the `patterns`.
+    ///
+    /// `connect` is called whenever a new connection is needed and it should return a future
+    /// resolving to `Client`.
+    pub fn new(mut connect: F, patterns: Vec<Pattern>, backoff: Backoff) -> Self {
+        let connection = connect().into_future();
+
+        Reconnecting {
+            connect,
+            patterns,
+            delay: backoff.initial,
+            backoff,
+            state: State::Connecting(connection),
+            values: HashMap::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    fn disconnected(&mut self, error: ProtocolError<E>) {
+        let retry_in = self.delay;
+        self.delay = std::cmp::min(self.delay * self.backoff.multiplier, self.backoff.max);
+        self.state = State::Waiting(Delay::new(Instant::now() + retry_in));
+        self.events.push_back(Event::Disconnected { error, retry_in });
+    }
+
+    /// Remembers the changes and reports them.
+    fn changed(&mut self, changes: Vec<(String, Option<Val>)>) {
+        for (key, value) in &changes {
+            match value {
+                Some(value) => { self.values.insert(key.clone(), value.clone()); },
+                None => { self.values.remove(key); },
+            }
+        }
+        self.events.push_back(Event::Changes(changes));
+    }
+
+    /// Reports the differences between the last known values and the current ones.
+    fn resynchronized(&mut self, current: Vec<(String, Option<Val>)>) {
+        let mut changes = Vec::new();
+        let mut present = HashSet::new();
+
+        for (key, value) in current {
+            if let Some(value) = value {
+                if self.values.get(&key) != Some(&value) {
+                    changes.push((key.clone(), Some(value)));
+                }
+                present.insert(key);
+            }
+        }
+
+        // Keys not present in the current values were deleted while disconnected.
+        let mut deleted = self.values
+            .keys()
+            .filter(|key| !present.contains(*key))
+            .cloned()
+            .collect::<Vec<_>>();
+        deleted.sort();
+        changes.extend(deleted.into_iter().map(|key| (key, None)));
+
+        self.events.push_back(Event::Connected);
+        if !changes.is_empty() {
+            self.changed(changes);
+        }
+    }
+}
+
+impl<F, Fut, C, Val, E> Stream for Reconnecting<F, Fut, C, Val, E> where
+    F: FnMut() -> Fut,
+    Fut: IntoFuture<Item=Client<C>, Error=ProtocolError<E>>,
+    C: 'static + Stream<Item=Response<Val>, Error=E> + Sink<SinkItem=Request<Val>, SinkError=E> + Send,
+    Val: 'static + Serialize + for<'a> Deserialize<'a> + Clone + PartialEq + Send,
+    E: 'static + Send {
+
+    type Item = Event<Val, E>;
+    type Error = tokio_timer::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Async::Ready(Some(event)));
+            }
+
+            let result = match &mut self.state {
+                State::Waiting(delay) => {
+                    futures::try_ready!(delay.poll());
+                    self.state = State::Connecting((self.connect)().into_future());
+                    continue;
+                },
+                State::Connecting(connection) => connection.poll().map(|result| result.map(Step::Connected)),
+                State::Subscribing(subscribing) => subscribing.poll().map(|result| result.map(Step::Subscribed)),
+                State::Connected(connection) => match connection.poll() {
+                    Ok(Async::Ready(Some(response))) => {
+                        if let Some(changes) = message::response_changes(response) {
+                            self.changed(changes);
+                        }
+                        continue;
+                    },
+                    Ok(Async::Ready(None)) => Err(ProtocolError::UnexpectedEof),
+                    Ok(Async::NotReady) => Ok(Async::NotReady),
+                    Err(error) => Err(ProtocolError::Communication(error)),
+                },
+            };
+
+            match result {
+                Ok(Async::Ready(Step::Connected(client))) => {
+                    let subscribing = client.subscribe_all(self.patterns.clone(), true);
+
+                    self.state = State::Subscribing(Box::new(subscribing));
+                },
+                Ok(Async::Ready(Step::Subscribed(client))) => {
+                    // Current values are received before the replies to subscription requests.
+                    let Client { connection, notifications, .. } = client;
+                    let current = notifications
+                        .into_iter()
+                        .filter_map(message::response_changes)
+                        .flatten()
+                        .collect();
+
+                    self.delay = self.backoff.initial;
+                    self.state = State::Connected(connection);
+                    self.resynchronized(current);
+                },
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(error) => self.disconnected(error),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, Event, Reconnecting};
+    use crate::{Client, Pattern, ProtocolError};
+    use dscfg_proto::{json, Request, Response, Reply};
+    use futures::{future, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Connection replaying prepared responses and recording sent requests.
+    ///
+    /// If `keep_open` is `false`, the connection is lost after all responses are received.
+    struct Connection {
+        responses: VecDeque<Response>,
+        keep_open: bool,
+        requests: Arc<Mutex<Vec<json::Value>>>,
+    }
+
+    impl Stream for Connection {
+        type Item = Response;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            match self.responses.pop_front() {
+                Some(response) => Ok(Async::Ready(Some(response))),
+                None if self.keep_open => Ok(Async::NotReady),
+                None => Ok(Async::Ready(None)),
+            }
+        }
+    }
+
+    impl Sink for Connection {
+        type SinkItem = Request;
+        type SinkError = ();
+
+        fn start_send(&mut self, request: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+            self.requests.lock().unwrap().push(json::to_value(&request.operation).unwrap());
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    type Connecting = Box<dyn Future<Item=Client<Connection>, Error=ProtocolError<()>> + Send>;
+
+    /// Responses of a server accepting the connection and subscription with `current` values.
+    fn subscribed(current: &[(&str, i32)], changes: &[(&str, i32)]) -> VecDeque<Response> {
+        let notification = |(key, value): &(&str, i32)| Response::Notification { key: key.to_string(), value: json::Value::from(*value) };
+        let welcome = Reply::Welcome { version: dscfg_proto::PROTOCOL_VERSION, capabilities: Vec::new() };
+
+        let mut responses = VecDeque::new();
+        responses.push_back(Response::Reply { id: 0, reply: welcome });
+        responses.extend(current.iter().map(notification));
+        responses.push_back(Response::Reply { id: 1, reply: Reply::OperationOk });
+        responses.extend(changes.iter().map(notification));
+        responses
+    }
+
+    fn change(key: &str, value: Option<i32>) -> (String, Option<json::Value>) {
+        (key.to_owned(), value.map(json::Value::from))
+    }
+
+    #[test]
+    fn resubscribes_after_connection_lost() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let mut connections = VecDeque::new();
+        // The connection is lost after a change is received.
+        connections.push_back(Some(Connection {
+            responses: subscribed(&[("a", 1), ("c", 1)], &[("a", 2)]),
+            keep_open: false,
+            requests: Arc::clone(&requests),
+        }));
+        // The server isn't running.
+        connections.push_back(None);
+        // `a` didn't change while disconnected, `b` was added and `c` was deleted.
+        connections.push_back(Some(Connection {
+            responses: subscribed(&[("a", 2), ("b", 5)], &[]),
+            keep_open: true,
+            requests: Arc::clone(&requests),
+        }));
+
+        let connect = move || -> Connecting {
+            match connections.pop_front().expect("too many attempts to connect") {
+                Some(connection) => Box::new(Client::custom(connection)),
+                None => Box::new(future::err(ProtocolError::Communication(()))),
+            }
+        };
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+            multiplier: 2,
+        };
+        let reconnecting = Reconnecting::new(connect, vec![Pattern::Prefix(String::new())], backoff);
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let events = runtime.block_on(reconnecting.take(7).collect()).unwrap();
+
+        match &events[..] {
+            [
+                Event::Connected,
+                Event::Changes(current),
+                Event::Changes(changed),
+                Event::Disconnected { error: ProtocolError::UnexpectedEof, retry_in: first_delay },
+                Event::Disconnected { error: ProtocolError::Communication(()), retry_in: second_delay },
+                Event::Connected,
+                Event::Changes(resynchronized),
+            ] => {
+                assert_eq!(*current, [change("a", Some(1)), change("c", Some(1))]);
+                assert_eq!(*changed, [change("a", Some(2))]);
+                assert_eq!(*first_delay, Duration::from_millis(10));
+                assert_eq!(*second_delay, Duration::from_millis(20));
+                assert_eq!(*resynchronized, [change("b", Some(5)), change("c", None)]);
+            },
+            _ => panic!("unexpected events: {:?}", events),
+        }
+
+        let subscribe = json::json!({ "Subscribe": { "pattern": { "Prefix": "" }, "notify_now": true } });
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[1], subscribe);
+        assert_eq!(requests[3], subscribe);
+    }
+}
@@ -0,0 +1,130 @@
+//! Default encoding of messages
+//!
+//! Each message is encoded as Json prefixed by its length as native-endian `u32`. Messages longer
+//! than `MAX_MESSAGE_LEN` are refused in both directions. The functions don't perform any I/O, so
+//! the clients and the servers can use them with any kind of stream.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::io;
+use MAX_MESSAGE_LEN;
+
+/// Length of the header containing the length of the message.
+pub const HEADER_LEN: usize = 4;
+
+/// Appends the message prefixed with its length to `buf`.
+///
+/// The `buf` is left unchanged if encoding fails.
+pub fn encode_into<T: Serialize>(message: &T, buf: &mut Vec<u8>) -> io::Result<()> {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; HEADER_LEN]);
+
+    if let Err(err) = ::serde_json::to_writer(&mut *buf, message) {
+        buf.truncate(start);
+        return Err(err.into());
+    }
+
+    let len = buf.len() - start - HEADER_LEN;
+    if len > MAX_MESSAGE_LEN {
+        buf.truncate(start);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too long"));
+    }
+    buf[start..(start + HEADER_LEN)].copy_from_slice(&(len as u32).to_ne_bytes());
+
+    Ok(())
+}
+
+/// Encodes the message into a frame prefixed with its length.
+///
+/// The frame should be written at once, which avoids sending the length in a separate packet.
+pub fn encode<T: Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    let mut frame = Vec::new();
+    encode_into(message, &mut frame)?;
+    Ok(frame)
+}
+
+/// Returns the length of the message following the `header`.
+pub fn message_len(header: [u8; HEADER_LEN]) -> io::Result<usize> {
+    let len = u32::from_ne_bytes(header) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message too long"));
+    }
+
+    Ok(len)
+}
+
+/// Decodes the message without the header.
+pub fn decode<T: DeserializeOwned>(message: &[u8]) -> io::Result<T> {
+    ::serde_json::from_slice(message).map_err(Into::into)
+}
+
+/// Decodes the first message in `buf` and removes it from the buffer.
+///
+/// Returns `None` if the buffer doesn't contain the whole message yet.
+pub fn decode_from<T: DeserializeOwned>(buf: &mut Vec<u8>) -> io::Result<Option<T>> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+
+    let mut header = [0; HEADER_LEN];
+    header.copy_from_slice(&buf[..HEADER_LEN]);
+    let end = HEADER_LEN + message_len(header)?;
+    if buf.len() < end {
+        return Ok(None);
+    }
+
+    let message = decode(&buf[HEADER_LEN..end])?;
+    buf.drain(..end);
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_from, encode, encode_into, message_len, HEADER_LEN};
+    use MAX_MESSAGE_LEN;
+
+    #[test]
+    fn frame() {
+        let frame = encode(&"hello").unwrap();
+        let mut header = [0; HEADER_LEN];
+        header.copy_from_slice(&frame[..HEADER_LEN]);
+
+        assert_eq!(message_len(header).unwrap(), frame.len() - HEADER_LEN);
+        assert_eq!(decode::<String>(&frame[HEADER_LEN..]).unwrap(), "hello");
+    }
+
+    #[test]
+    fn oversized() {
+        let mut buf = encode(&"hello").unwrap();
+        let len = buf.len();
+
+        assert!(encode_into(&"x".repeat(MAX_MESSAGE_LEN), &mut buf).is_err());
+        assert_eq!(buf.len(), len);
+
+        let header = ((MAX_MESSAGE_LEN + 1) as u32).to_ne_bytes();
+        assert!(message_len(header).is_err());
+        assert!(decode_from::<String>(&mut header.to_vec()).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut frames = encode(&"hello").unwrap();
+        encode_into(&"world", &mut frames).unwrap();
+        let first_len = frames.len() / 2;
+
+        for len in &[0, HEADER_LEN - 1, HEADER_LEN, first_len - 1] {
+            let mut buf = frames[..*len].to_vec();
+            assert_eq!(decode_from::<String>(&mut buf).unwrap(), None);
+            assert_eq!(buf.len(), *len);
+        }
+
+        let mut buf = frames[..(first_len + 1)].to_vec();
+        assert_eq!(decode_from::<String>(&mut buf).unwrap(), Some("hello".to_owned()));
+        assert_eq!(buf.len(), 1);
+        assert_eq!(decode_from::<String>(&mut buf).unwrap(), None);
+
+        assert_eq!(decode_from::<String>(&mut frames).unwrap(), Some("hello".to_owned()));
+        assert_eq!(decode_from::<String>(&mut frames).unwrap(), Some("world".to_owned()));
+        assert!(frames.is_empty());
+    }
+}
@@ -19,6 +19,8 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 
+use std::fmt;
+
 /// Reexport for `serde_json`
 ///
 /// This is mainly useful to avoid having to specify another dependency.
@@ -27,35 +29,458 @@ pub mod json {
     pub use ::serde_json::*;
 }
 
+/// Version of the protocol defined by this crate.
+///
+/// The version is exchanged in `Hello` and `Welcome` messages when
+/// a connection is established. It's incremented whenever a change
+/// in the protocol would make the client and the server incompatible.
+/// Compatible additions are announced as capabilities instead.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// Maximum length of a single message in the default encoding.
+///
+/// Messages are encoded as Json prefixed by their length as native-endian `u32`,
+/// see `framing`. Longer messages are refused by both the client and the server.
+pub const MAX_MESSAGE_LEN: usize = 8 * 1024 * 1024;
+
+pub mod framing;
+
+/// Names of optional capabilities negotiated during handshake.
+///
+/// The client announces which capabilities it'd like to use in
+/// `Hello` and the server responds with those it supports in `Welcome`.
+/// The client must not send requests requiring a capability which
+/// wasn't negotiated.
+pub mod capability {
+    /// Allows sending `Operation::Delete`.
+    pub const DELETE: &str = "delete";
+
+    /// Allows sending `Operation::List`.
+    pub const LIST: &str = "list";
+
+    /// Allows sending `Operation::CompareAndSet`.
+    pub const COMPARE_AND_SET: &str = "compare_and_set";
+
+    /// Allows sending `Operation::Transaction`.
+    pub const TRANSACTION: &str = "transaction";
+
+    /// Allows sending `Operation::SubscribeMany`.
+    pub const SUBSCRIBE_MANY: &str = "subscribe_many";
+
+    /// Allows sending `Operation::GetMany` and `Operation::GetPrefix`.
+    pub const GET_MANY: &str = "get_many";
+
+    /// Allows the server to reply with `Reply::PermissionDenied`.
+    ///
+    /// Clients which didn't negotiate it get `Reply::Error` with
+    /// `ErrorCode::PermissionDenied` (or `Reply::OperationFailed`)
+    /// instead.
+    pub const PERMISSION_DENIED: &str = "permission_denied";
+
+    /// Allows the server to distinguish missing keys from `null` values
+    /// using `Reply::NotFound` and `Response::Deleted`.
+    ///
+    /// Clients which didn't negotiate it get `null` values of missing
+    /// keys instead.
+    pub const NOT_FOUND: &str = "not_found";
+
+    /// Allows the server to describe failures using `Reply::Error`.
+    ///
+    /// Clients which didn't negotiate it get `Reply::OperationFailed`
+    /// instead.
+    pub const ERRORS: &str = "errors";
+
+    /// Allows the server to notify about several changes at once using
+    /// `Response::Changes`.
+    ///
+    /// Clients which didn't negotiate it are notified about each change
+    /// with a separate `Notification` or `Deleted`.
+    pub const CHANGES: &str = "changes";
+}
+
+/// Identifier of a request.
+///
+/// It's chosen by the client and the server echoes it in the reply,
+/// so the client can match replies to requests.
+pub type RequestId = u64;
+
+/// Machine-readable reason of failure.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub enum ErrorCode {
+    /// The server failed to access the storage.
+    StorageFailure,
+
+    /// The client isn't allowed to perform the operation.
+    PermissionDenied,
+
+    /// The request is invalid.
+    ValidationFailed,
+
+    /// The operation conflicts with the current state.
+    ///
+    /// Note that `CompareAndSet` and `Transaction` use a dedicated
+    /// `Conflict` reply, which contains the current state.
+    Conflict,
+
+    /// The server doesn't support the operation.
+    ///
+    /// This happens if the client sends a request requiring
+    /// a capability that wasn't negotiated.
+    Unsupported,
+
+    /// The server didn't describe the failure.
+    ///
+    /// This is never sent by the server, clients use it for
+    /// `Reply::OperationFailed`.
+    Unknown,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            ErrorCode::StorageFailure => "storage failure",
+            ErrorCode::PermissionDenied => "permission denied",
+            ErrorCode::ValidationFailed => "validation failed",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::Unsupported => "unsupported",
+            ErrorCode::Unknown => "unknown",
+        };
+
+        f.write_str(description)
+    }
+}
+
+/// Kind of access to a key.
+///
+/// The server may refuse operations requiring a permission the client
+/// doesn't have with `Reply::PermissionDenied`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub enum Permission {
+    /// Getting and listing keys, as well as checking conditions of
+    /// `CompareAndSet` and `Transaction`.
+    Read,
+
+    /// Setting and deleting keys.
+    Write,
+
+    /// Receiving notifications about changes of keys.
+    Subscribe,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Subscribe => "subscribe",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// Revision of a key.
+///
+/// Revisions are assigned by the server whenever the key changes. They
+/// are opaque - the only meaningful operation is comparing them for
+/// equality. Revision `0` is used for keys that didn't change since the
+/// server started.
+pub type Revision = u64;
+
+/// Condition which must hold for `CompareAndSet` to succeed.
+#[cfg_attr(feature = "client", derive(Serialize))]
+#[cfg_attr(feature = "server", derive(Deserialize))]
+pub enum Expected<Val = json::Value> {
+    /// The key must have the given revision.
+    Revision(Revision),
+
+    /// The key must have the given value.
+    Value(Val),
+
+    /// The key must not exist.
+    Absent,
+}
+
+/// Condition which must hold for a transaction to be applied.
+#[cfg_attr(feature = "client", derive(Serialize))]
+#[cfg_attr(feature = "server", derive(Deserialize))]
+pub struct Condition<Val = json::Value> {
+    /// The key to check.
+    pub key: String,
+    /// The expected state of the key.
+    pub expected: Expected<Val>,
+}
+
+/// Change of a single key.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(any(feature = "client", feature = "server"), derive(Serialize, Deserialize))]
+pub enum Change<Val = json::Value> {
+    /// The value of `key` is set to `value`.
+    Set { key: String, value: Val },
+
+    /// The `key` is deleted.
+    Delete { key: String },
+}
+
+impl<Val> Change<Val> {
+    /// Returns the key affected by this change.
+    pub fn key(&self) -> &str {
+        match self {
+            Change::Set { key, .. } => key,
+            Change::Delete { key } => key,
+        }
+    }
+}
+
+/// State of a single key returned by `GetMany` and `GetPrefix`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub enum Entry<Val = json::Value> {
+    /// The `key` exists and has the given `value` and `revision`.
+    Present { key: String, value: Val, revision: Revision },
+
+    /// The `key` doesn't exist.
+    ///
+    /// This is distinct from the key having `null` value.
+    Absent { key: String },
+}
+
+impl<Val> Entry<Val> {
+    /// Returns the key this entry describes.
+    pub fn key(&self) -> &str {
+        match self {
+            Entry::Present { key, .. } => key,
+            Entry::Absent { key } => key,
+        }
+    }
+}
+
+/// Specification of keys a client subscribes to.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "client", derive(Serialize))]
+#[cfg_attr(feature = "server", derive(Deserialize))]
+pub enum Pattern {
+    /// Matches exactly the given key.
+    Key(String),
+
+    /// Matches all keys starting with the given string.
+    ///
+    /// For example `network.` matches `network.address` and
+    /// `network.port`.
+    Prefix(String),
+
+    /// Matches keys using shell-like wildcards.
+    ///
+    /// `*` matches any sequence of characters (including `.` and
+    /// empty sequence) and `?` matches exactly one character. All other
+    /// characters match themselves.
+    Glob(String),
+}
+
+impl Pattern {
+    /// Returns `true` if the `key` matches this pattern.
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            Pattern::Key(pattern) => pattern == key,
+            Pattern::Prefix(prefix) => key.starts_with(prefix.as_str()),
+            Pattern::Glob(glob) => glob_matches(glob, key),
+        }
+    }
+
+    /// Returns the longest string all matching keys start with.
+    ///
+    /// This is useful for looking up the keys matching the pattern.
+    pub fn literal_prefix(&self) -> &str {
+        match self {
+            Pattern::Key(key) => key,
+            Pattern::Prefix(prefix) => prefix,
+            Pattern::Glob(glob) => &glob[..glob.find(['*', '?']).unwrap_or(glob.len())],
+        }
+    }
+}
+
+impl From<String> for Pattern {
+    fn from(key: String) -> Self {
+        Pattern::Key(key)
+    }
+}
+
+impl<'a> From<&'a str> for Pattern {
+    fn from(key: &'a str) -> Self {
+        Pattern::Key(key.to_owned())
+    }
+}
+
+fn glob_matches(glob: &str, key: &str) -> bool {
+    let glob = glob.chars().collect::<Vec<_>>();
+    let key = key.chars().collect::<Vec<_>>();
+    let (mut g, mut k) = (0, 0);
+    // Position of the last `*` in glob and the position in key it's currently matched up to.
+    let mut backtrack = None;
+
+    while k < key.len() {
+        if g < glob.len() && glob[g] == '*' {
+            backtrack = Some((g, k));
+            g += 1;
+        } else if g < glob.len() && (glob[g] == '?' || glob[g] == key[k]) {
+            g += 1;
+            k += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the `*` consume one more character.
+            backtrack = Some((star, matched + 1));
+            g = star + 1;
+            k = matched + 1;
+        } else {
+            return false;
+        }
+    }
+
+    glob[g..].iter().all(|c| *c == '*')
+}
+
 /// Request sent from client to server.
 ///
+/// This struct is parametric over value type in order to skip conversions
+/// to `json::Value` when sending the request.
+#[cfg_attr(feature = "client", derive(Serialize))]
+#[cfg_attr(feature = "server", derive(Deserialize))]
+pub struct Request<Val = json::Value> {
+    /// Identifier of the request sent back in `Response::Reply`.
+    pub id: RequestId,
+    /// The operation the server should perform.
+    pub operation: Operation<Val>,
+}
+
+/// Operation requested by the client.
+///
 /// This enum represents possible requests accepted by the server.
 /// See the documentation of its variants to understand possible requests.
-///
-/// This enum is parametric over value type in order to skip conversions
-/// to `json::Value` when sending the request.
 #[cfg_attr(feature = "client", derive(Serialize))]
 #[cfg_attr(feature = "server", derive(Deserialize))]
-pub enum Request<Val = json::Value> {
+pub enum Operation<Val = json::Value> {
+    /// Initiates the connection.
+    ///
+    /// This must be the first request sent by the client. The server
+    /// responds with `Welcome` if it supports the `version` or with
+    /// `UnsupportedVersion` otherwise, in which case it closes the
+    /// connection.
+    ///
+    /// `capabilities` contains the names of optional features the
+    /// client would like to use.
+    Hello { version: u32, capabilities: Vec<String> },
+
     /// Sets the value of `key` to `value`
     ///
-    /// There's no response, but if the client is subscribed
-    /// with the `key`, it will get the notification.
+    /// The reply is `OperationOk` if the value was stored or
+    /// `Error` otherwise. If the client is subscribed
+    /// with the `key`, it will get the notification as well.
     Set { key: String, value: Val },
 
     /// Gets the value of the `key`
     ///
-    /// Response of type `Value` follows this request.
+    /// The reply is `Value` if the key exists, `NotFound` if it
+    /// doesn't or `Error` if reading failed.
     Get { key: String },
 
-    /// Requests notifications when any of the keys change.
+    /// Gets the values of all `keys` at once.
+    ///
+    /// The reply is `Values` containing an entry for each requested
+    /// key in the same order or `Error` if reading failed. The values
+    /// are read atomically - no change can happen between reading
+    /// them.
+    ///
+    /// Requires `capability::GET_MANY`.
+    GetMany { keys: Vec<String> },
+
+    /// Gets the values of all existing keys starting with `prefix`.
+    ///
+    /// The reply is `Values` with entries sorted by keys, none of
+    /// them `Absent`, or `Error` if reading failed. Just like with
+    /// `GetMany`, the values are read atomically.
+    ///
+    /// Requires `capability::GET_MANY`.
+    GetPrefix { prefix: String },
+
+    /// Sets the value of `key` to `value` if the `expected` condition
+    /// holds.
+    ///
+    /// The check and the update are performed atomically. The reply is
+    /// `Updated` if the value was stored, `Conflict` if the condition
+    /// didn't hold, `NotFound` if it didn't hold because the key
+    /// doesn't exist or `Error` if accessing the storage failed.
+    ///
+    /// Requires `capability::COMPARE_AND_SET`.
+    CompareAndSet { key: String, expected: Expected<Val>, value: Val },
+
+    /// Deletes the `key` along with its value.
+    ///
+    /// The reply is `OperationOk` if the key was deleted, `Ignored`
+    /// if it didn't exist or `Error` if deleting failed.
+    /// Subscribed clients are notified with `Deleted`.
+    ///
+    /// Requires `capability::DELETE`.
+    Delete { key: String },
+
+    /// Atomically applies all `changes` if all `conditions` hold.
+    ///
+    /// Either all changes are applied or none of them. Subscribed
+    /// clients are notified about all changes they are interested in
+    /// with a single `Changes` notification.
+    ///
+    /// The reply is `Updated` if the changes were applied (all changed
+    /// keys get the same revision), `Conflict` with the first key for
+    /// which the condition didn't hold (`NotFound` if the key doesn't
+    /// exist) or `Error` if accessing the storage failed. Deleting
+    /// keys that don't exist has no effect and if no change has any
+    /// effect, no key gets a new revision and `Updated` contains the
+    /// latest revision assigned by the server.
+    ///
+    /// Requires `capability::TRANSACTION`.
+    Transaction { conditions: Vec<Condition<Val>>, changes: Vec<Change<Val>> },
+
+    /// Lists existing keys starting with `prefix`.
+    ///
+    /// Empty `prefix` lists all keys. The reply is `Keys` or
+    /// `Error` if reading failed.
+    ///
+    /// Requires `capability::LIST`.
+    List { prefix: String },
+
+    /// Requests notifications when any of the keys matching the
+    /// `pattern` change.
     ///
     /// If `notify_now` is set to `true`, the client is
-    /// also notified immediately after the response.
+    /// also notified immediately about all existing matching keys.
+    /// If the `pattern` is a single key which doesn't exist, the
+    /// client is notified with `Deleted` (or `null` value if
+    /// `capability::NOT_FOUND` wasn't negotiated).
     ///
-    /// The response is either `OperationOk`, if the cliet was
-    /// subscribed or `Ignored`, if the client was already subscribed.
-    Subscribe { key: String, notify_now: bool },
+    /// The reply is either `OperationOk`, if the cliet was
+    /// subscribed or `Ignored`, if the client was already subscribed
+    /// with the same pattern.
+    Subscribe { pattern: Pattern, notify_now: bool },
+
+    /// Requests notifications when any of the keys matching any of
+    /// the `patterns` change.
+    ///
+    /// This is the same as sending `Subscribe` for each pattern,
+    /// except that there's a single reply and if `notify_now` is set
+    /// to `true`, the current values of all matching keys are sent in
+    /// a single `Changes` notification before the reply. Each key is
+    /// included only once even if it matches several patterns.
+    /// Single keys that don't exist are included as `Change::Delete`.
+    ///
+    /// The reply is `OperationOk` if the client was subscribed with
+    /// at least one new pattern or `Ignored` if it was already
+    /// subscribed with all of them.
+    ///
+    /// Requires `capability::SUBSCRIBE_MANY`.
+    SubscribeMany { patterns: Vec<Pattern>, notify_now: bool },
 
     /// Requests the server to stop notifying the client
     ///
@@ -63,25 +488,161 @@ pub enum Request<Val = json::Value> {
     /// disconnect - the subscribtions of the client are automatically
     /// cleared on disconnect.
     ///
+    /// The `pattern` must be the same as the one used in `Subscribe`.
+    ///
     /// If the unsubscribe operation was performed, `OperationOk`
-    /// response is sent. If the client wasn't subscribed,
+    /// reply is sent. If the client wasn't subscribed,
     /// `Ignored` is sent.
-    Unsubscribe { key: String },
+    Unsubscribe { pattern: Pattern },
+}
+
+impl<Val> Operation<Val> {
+    /// Returns the capability that must be negotiated in order to
+    /// send this operation.
+    pub fn required_capability(&self) -> Option<&'static str> {
+        match self {
+            Operation::CompareAndSet { .. } => Some(capability::COMPARE_AND_SET),
+            Operation::Delete { .. } => Some(capability::DELETE),
+            Operation::Transaction { .. } => Some(capability::TRANSACTION),
+            Operation::List { .. } => Some(capability::LIST),
+            Operation::SubscribeMany { .. } => Some(capability::SUBSCRIBE_MANY),
+            Operation::GetMany { .. } |
+            Operation::GetPrefix { .. } => Some(capability::GET_MANY),
+            Operation::Hello { .. } |
+            Operation::Set { .. } |
+            Operation::Get { .. } |
+            Operation::Subscribe { .. } |
+            Operation::Unsubscribe { .. } => None,
+        }
+    }
 }
 
 /// Response or notification sent to the client.
+#[derive(Clone)]
 #[cfg_attr(feature = "server", derive(Serialize))]
 #[cfg_attr(feature = "client", derive(Deserialize))]
 pub enum Response<Val = json::Value> {
-    /// Informs the client about the value for certain key.
-    Value { key: String, value: Val },
+    /// Reply to the request identified by `id`.
+    Reply { id: RequestId, reply: Reply<Val> },
+
+    /// Informs the client that the value of `key` changed to `value`.
+    ///
+    /// Notifications are sent only to subscribed clients and they
+    /// aren't replies to any particular request.
+    Notification { key: String, value: Val },
+
+    /// Informs the client that the `key` was deleted.
+    ///
+    /// This is distinct from setting the value to `null`. Just like
+    /// `Notification`, it's sent only to subscribed clients.
+    Deleted { key: String },
+
+    /// Informs the client about several changes made by a single
+    /// transaction.
+    ///
+    /// Only the changes of the keys the client is subscribed to are
+    /// included. Sent only to clients which negotiated
+    /// `capability::CHANGES`.
+    Changes { changes: Vec<Change<Val>> },
+}
+
+/// Reply to a request.
+///
+/// See the documentation of `Operation` variants to find out which
+/// replies may be sent.
+#[derive(Clone)]
+#[cfg_attr(feature = "server", derive(Serialize))]
+#[cfg_attr(feature = "client", derive(Deserialize))]
+pub enum Reply<Val = json::Value> {
+    /// Accepts the connection.
+    ///
+    /// `version` is the version of the protocol the server will use
+    /// and `capabilities` contains those capabilities requested by
+    /// the client which the server supports.
+    Welcome { version: u32, capabilities: Vec<String> },
+
+    /// Informs the client that the server doesn't support the version
+    /// of the protocol sent in `Hello`.
+    ///
+    /// `version` is the version the server supports. The connection
+    /// is closed after this response.
+    UnsupportedVersion { version: u32 },
+
+    /// Informs the client about the value and revision for certain key.
+    ///
+    /// Servers which don't support `capability::COMPARE_AND_SET` may
+    /// omit the revision, in which case it's `0`.
+    Value {
+        key: String,
+        value: Val,
+        #[cfg_attr(feature = "client", serde(default))]
+        revision: Revision,
+    },
+
+    /// Informs the client that the `key` doesn't exist.
+    ///
+    /// This is distinct from the key having `null` value. `revision`
+    /// is the revision of the key, which is changed by deleting it.
+    ///
+    /// This is the reply to `Get` and also to `CompareAndSet` and
+    /// `Transaction` if the condition didn't hold because the key
+    /// doesn't exist. Clients which didn't negotiate
+    /// `capability::NOT_FOUND` get `Value` or `Conflict` with `null`
+    /// value instead.
+    NotFound { key: String, revision: Revision },
+
+    /// Informs the client about the state of several keys.
+    ///
+    /// This is the reply to `GetMany` and `GetPrefix`.
+    Values { values: Vec<Entry<Val>> },
+
+    /// Informs the client that the value (or values) was updated and
+    /// it has the new `revision`.
+    Updated { revision: Revision },
+
+    /// Informs the client that `CompareAndSet` or `Transaction` failed
+    /// because the current state of the existing key differs from the
+    /// expected one.
+    ///
+    /// `value` and `revision` are the current value and revision
+    /// of the key.
+    Conflict { key: String, value: Val, revision: Revision },
+
+    /// Informs the client about existing keys.
+    ///
+    /// The keys are sorted.
+    Keys { keys: Vec<String> },
 
     /// Informs the client that the operation was performed.
     OperationOk,
 
-    /// Informs the client that the operation failed.
+    /// Informs the client that it isn't allowed to access the `key`
+    /// in the way the operation requires.
+    ///
+    /// This may be the reply to any operation accessing particular
+    /// keys, but operations accessing keys by prefix (`List`,
+    /// `GetPrefix`) or subscriptions with `Pattern::Prefix` and
+    /// `Pattern::Glob` silently skip the keys the client isn't allowed
+    /// to access instead. Nothing is performed if the permission is
+    /// denied for any of the keys.
+    ///
+    /// Sent only to clients which negotiated
+    /// `capability::PERMISSION_DENIED`.
+    PermissionDenied { key: String, permission: Permission },
+
+    /// Informs the client that the operation failed without
+    /// describing the failure.
+    ///
+    /// This is sent instead of `Error` to clients which didn't
+    /// negotiate `capability::ERRORS`.
     OperationFailed,
 
+    /// Informs the client that the operation failed.
+    ///
+    /// `code` describes the kind of failure and `message` is a human
+    /// readable description of it.
+    Error { code: ErrorCode, message: String },
+
     /// Informs the client that the operation didn't have to be
     /// performed.
     ///
@@ -90,3 +651,39 @@ pub enum Response<Val = json::Value> {
     /// isn't subscribed to.
     Ignored,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Pattern;
+
+    #[test]
+    fn prefix() {
+        let pattern = Pattern::Prefix("network.".to_owned());
+
+        assert!(pattern.matches("network.port"));
+        assert!(pattern.matches("network."));
+        assert!(!pattern.matches("network"));
+        assert!(!pattern.matches("display.network.port"));
+    }
+
+    #[test]
+    fn glob() {
+        let pattern = Pattern::Glob("network.*.mtu".to_owned());
+
+        assert!(pattern.matches("network.eth0.mtu"));
+        assert!(pattern.matches("network..mtu"));
+        assert!(pattern.matches("network.eth0.vlan1.mtu"));
+        assert!(!pattern.matches("network.eth0.mtux"));
+        assert!(!pattern.matches("network.mtu"));
+        assert_eq!(pattern.literal_prefix(), "network.");
+
+        let pattern = Pattern::Glob("a?c*".to_owned());
+
+        assert!(pattern.matches("abc"));
+        assert!(pattern.matches("abcdef"));
+        assert!(!pattern.matches("ac"));
+        assert!(Pattern::Glob("*".to_owned()).matches(""));
+        assert!(Pattern::Glob("**a".to_owned()).matches("bba"));
+        assert!(!Pattern::Glob("".to_owned()).matches("a"));
+    }
+}
@@ -0,0 +1,237 @@
+//! Access control lists
+//!
+//! `Acl` is an `Authorizer` granting permissions according to a list of rules. Access which isn't
+//! granted by any rule is denied.
+//!
+//! The rules are usually loaded from a policy file. Each line of the file contains one rule
+//! consisting of comma-separated permissions, a key pattern and a principal, separated by
+//! whitespace. Empty lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! # permissions         pattern     principal
+//! read,write,subscribe  *           uid:0
+//! write                 network.*   group:netadmin
+//! read,subscribe        *           *
+//! ```
+//!
+//! The permissions are `read`, `write` and `subscribe`. The pattern is a glob if it contains `*`
+//! or `?`, otherwise it's a single key. The principal is the rest of the line, so it may contain
+//! spaces. Principal `*` matches all clients, other principals are matched using
+//! `Identity::has_principal()`.
+
+use super::{Authorizer, Identity, Permission};
+use dscfg_proto::Pattern;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Grants the `permissions` for the keys matching the `pattern` to clients with the `principal`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Rule {
+    /// Permissions granted by this rule.
+    pub permissions: Vec<Permission>,
+    /// Keys this rule applies to.
+    pub pattern: Pattern,
+    /// Clients this rule applies to, `*` means all clients.
+    pub principal: String,
+}
+
+impl Rule {
+    fn grants(&self, identity: &dyn Identity, permission: Permission, key: &str) -> bool {
+        self.permissions.contains(&permission) &&
+            self.pattern.matches(key) &&
+            (self.principal == "*" || identity.has_principal(&self.principal))
+    }
+}
+
+impl FromStr for Rule {
+    type Err = ParseErrorKind;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (permissions, rest) = split_field(line);
+        let (pattern, principal) = split_field(rest);
+        if pattern.is_empty() {
+            return Err(ParseErrorKind::MissingPattern);
+        }
+        if principal.is_empty() {
+            return Err(ParseErrorKind::MissingPrincipal);
+        }
+
+        let permissions = permissions
+            .split(',')
+            .map(|permission| match permission {
+                "read" => Ok(Permission::Read),
+                "write" => Ok(Permission::Write),
+                "subscribe" => Ok(Permission::Subscribe),
+                _ => Err(ParseErrorKind::UnknownPermission(permission.to_owned())),
+            })
+            .collect::<Result<_, _>>()?;
+
+        let pattern = if pattern.contains(['*', '?']) {
+            Pattern::Glob(pattern.to_owned())
+        } else {
+            Pattern::Key(pattern.to_owned())
+        };
+
+        Ok(Rule {
+            permissions,
+            pattern,
+            principal: principal.to_owned(),
+        })
+    }
+}
+
+/// Returns the first whitespace-separated field of the `line` and the trimmed rest.
+fn split_field(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.find(char::is_whitespace) {
+        Some(pos) => (&line[..pos], line[pos..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+/// Authorizer granting permissions according to rules.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    /// Creates the list from `rules`.
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Acl {
+            rules,
+        }
+    }
+
+    /// Loads the rules from policy file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        std::fs::read_to_string(path)
+            .map_err(LoadError::Io)?
+            .parse()
+            .map_err(LoadError::Parse)
+    }
+
+    /// Returns the rules of this list.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+}
+
+impl FromStr for Acl {
+    type Err = ParseError;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        let rules = policy
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(number, line)| line.parse().map_err(|kind| ParseError { line: number + 1, kind }))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Acl::new(rules))
+    }
+}
+
+impl Authorizer for Acl {
+    fn is_allowed(&self, identity: &dyn Identity, permission: Permission, key: &str) -> bool {
+        self.rules.iter().any(|rule| rule.grants(identity, permission, key))
+    }
+}
+
+/// Reason why a rule is invalid.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseErrorKind {
+    /// The permission isn't one of `read`, `write` and `subscribe`.
+    UnknownPermission(String),
+    /// The rule contains only permissions.
+    MissingPattern,
+    /// The rule doesn't specify who it applies to.
+    MissingPrincipal,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnknownPermission(permission) => write!(f, "unknown permission \"{}\"", permission),
+            ParseErrorKind::MissingPattern => write!(f, "missing pattern"),
+            ParseErrorKind::MissingPrincipal => write!(f, "missing principal"),
+        }
+    }
+}
+
+/// Error returned when the policy contains an invalid rule.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    /// Number of the invalid line, starting at 1.
+    pub line: usize,
+    /// What's wrong with the rule.
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid rule on line {}: {}", self.line, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Error returned when loading policy file fails.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file couldn't be read.
+    Io(io::Error),
+    /// The file contains an invalid rule.
+    Parse(ParseError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "failed to read the policy: {}", err),
+            LoadError::Parse(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acl, ParseError, ParseErrorKind};
+    use crate::{Authorizer, Permission};
+
+    const POLICY: &str = "
+        # Administrator may do anything.
+        read,write,subscribe  *          root
+
+        write                 network.*  netadmin
+        read,subscribe        *          *
+    ";
+
+    #[test]
+    fn grants_matching_rules() {
+        let acl = POLICY.parse::<Acl>().unwrap();
+        let root = "root".to_owned();
+        let netadmin = "netadmin".to_owned();
+        let other = "other".to_owned();
+
+        assert!(acl.is_allowed(&root, Permission::Write, "display.brightness"));
+        assert!(acl.is_allowed(&netadmin, Permission::Write, "network.address"));
+        assert!(!acl.is_allowed(&netadmin, Permission::Write, "display.brightness"));
+        assert!(acl.is_allowed(&other, Permission::Read, "network.address"));
+        assert!(acl.is_allowed(&other, Permission::Subscribe, "network.address"));
+        assert!(!acl.is_allowed(&other, Permission::Write, "network.address"));
+    }
+
+    #[test]
+    fn reports_invalid_line() {
+        let err = "read * *\nexecute * *".parse::<Acl>().unwrap_err();
+        assert_eq!(err, ParseError { line: 2, kind: ParseErrorKind::UnknownPermission("execute".to_owned()) });
+
+        let err = "read *".parse::<Acl>().unwrap_err();
+        assert_eq!(err, ParseError { line: 1, kind: ParseErrorKind::MissingPrincipal });
+    }
+}
@@ -0,0 +1,372 @@
+//! Server built on `std::future::Future`
+//!
+//! This module provides the same server as the crate root, but it accepts clients from a `Stream`
+//! of the `futures` 0.3 crate and spawns them using a provided function, so it can be used with
+//! any runtime supporting `std::future::Future` without a compatibility layer.
+//!
+//! The `Storage`, subscriptions and handling of fatal errors behave exactly the same as in the
+//! futures 0.1 based server.
+
+use super::{Authentication, Authenticator, Authorizer, Identity, Storage, HandlingError, RequestHandler, Shared, Subscriptions, Revisions};
+use super::compatible_responses;
+use dscfg_proto::{framing, Request, Response};
+use futures::sync::mpsc;
+use futures03::compat::Stream01CompatExt;
+use futures03::future::{self, BoxFuture, Either, FutureExt};
+use futures03::io::{AsyncRead, AsyncWrite};
+use futures03::task::{Context, Poll};
+use futures03::{Sink, SinkExt, Stream, StreamExt};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::io;
+
+/// How many bytes are read at once.
+const READ_CHUNK_LEN: usize = 4096;
+
+/// How many bytes may be buffered before the messages are written out.
+const WRITE_BUFFER_LEN: usize = 64 * 1024;
+
+/// Parameters the server needs to run
+///
+/// This is the same as `dscfg_server::ServerParams`, except that clients are accepted from
+/// `futures` 0.3 `Stream` and handled using `spawn` function instead of executor.
+pub struct ServerParams<Incoming, Auth, Authz, Store, Spawn, Logger> where
+    Incoming: Stream,
+    Authz: 'static + Authorizer,
+    Store: Storage + Clone + Send,
+    Spawn: FnMut(BoxFuture<'static, ()>),
+    Logger: Into<slog::Logger> {
+
+    /// Clients that are accepted.
+    pub incoming_clients: Incoming,
+    /// Decides who the accepted clients are.
+    ///
+    /// It has to implement `Authenticator` for the successfully accepted clients.
+    pub authenticator: Auth,
+    /// Decides which keys the clients may access.
+    pub authorizer: Authz,
+    /// The implementation of configuration storage.
+    pub storage: Store,
+    /// Function used for spawning tasks handling the clients.
+    ///
+    /// For example, when using `tokio`, this can be `|client| { tokio::spawn(client); }`.
+    pub spawn: Spawn,
+    /// `slog` Logger used for logging.
+    pub logger: Logger,
+}
+
+/// Creates a server with custom client stream.
+///
+/// This may be used if one wants control over how the messages are serialized.
+/// If you want to use the default serialization (length-delimited json encoding),
+/// use `serve()` function.
+///
+/// The returned future resolves when there are no more incoming clients or when a fatal error
+/// occurs. Since spawning can't fail, `HandlingError::Shutdown` is never returned.
+pub async fn custom<Incoming, Client, AcceptError, Auth, Authz, Store, Spawn, Logger, CommError>(server_params: ServerParams<Incoming, Auth, Authz, Store, Spawn, Logger>) -> Result<(), HandlingError<AcceptError>> where
+    Incoming: Stream<Item=Result<Client, AcceptError>>,
+    Client: 'static + Stream<Item=Result<Request, CommError>> + Sink<Response, Error=CommError> + Unpin + Send,
+    Auth: Authenticator<Client>,
+    Authz: 'static + Authorizer,
+    Store: 'static + Storage + Clone + Send,
+    Spawn: FnMut(BoxFuture<'static, ()>),
+    Logger: Into<slog::Logger>,
+    CommError: 'static {
+
+    let mut authenticator = server_params.authenticator;
+    let incoming_clients = server_params.incoming_clients.map(move |client| client.map(|client| {
+        let authentication = super::authenticate(&mut authenticator, &client);
+        (client, authentication)
+    }));
+
+    serve_authenticated(incoming_clients, Arc::new(server_params.authorizer), server_params.storage, server_params.spawn, server_params.logger.into()).await
+}
+
+/// Runs the server with clients that were already authenticated.
+async fn serve_authenticated<Incoming, Client, AcceptError, Store, Spawn, CommError>(incoming_clients: Incoming, authorizer: Arc<dyn Authorizer>, storage: Store, mut spawn: Spawn, logger: slog::Logger) -> Result<(), HandlingError<AcceptError>> where
+    Incoming: Stream<Item=Result<(Client, Authentication), AcceptError>>,
+    Client: 'static + Stream<Item=Result<Request, CommError>> + Sink<Response, Error=CommError> + Unpin + Send,
+    Store: 'static + Storage + Clone + Send,
+    Spawn: FnMut(BoxFuture<'static, ()>),
+    CommError: 'static {
+
+    let shared = Shared {
+        subscriptions: Subscriptions::new(Arc::clone(&authorizer)),
+        revisions: Revisions::new(),
+        authorizer,
+    };
+    let (canceler, cancelable) = mpsc::unbounded();
+    let mut cancelable = cancelable.compat();
+
+    futures03::pin_mut!(incoming_clients);
+
+    loop {
+        let client = match future::select(incoming_clients.next(), cancelable.next()).await {
+            Either::Left((Some(Ok(client)), _)) => client,
+            Either::Left((Some(Err(err)), _)) => return Err(HandlingError::AcceptError(err)),
+            Either::Left((None, _)) => return Ok(()),
+            Either::Right(_) => {
+                info!(logger, "shutting down");
+                return Ok(());
+            },
+        };
+
+        let (client, authentication) = client;
+        let handler = handle_client(client, authentication, shared.clone(), storage.clone(), canceler.clone(), logger.clone());
+        spawn(Box::pin(handler));
+    }
+}
+
+/// Creates default dscfg server.
+///
+/// This server uses length-delimited Json messages to transfer the data, so it's compatible with
+/// the clients of the futures 0.1 based server. Use `custom()` if you want to control encoding.
+pub async fn serve<Incoming, Connection, AcceptError, Auth, Authz, Store, Spawn, Logger>(server_params: ServerParams<Incoming, Auth, Authz, Store, Spawn, Logger>) -> Result<(), HandlingError<AcceptError>> where
+    Incoming: Stream<Item=Result<Connection, AcceptError>>,
+    Connection: 'static + AsyncRead + AsyncWrite + Unpin + Send,
+    Auth: Authenticator<Connection>,
+    Authz: 'static + Authorizer,
+    Store: 'static + Storage + Clone + Send,
+    Spawn: FnMut(BoxFuture<'static, ()>),
+    Logger: Into<slog::Logger> {
+
+    let mut authenticator = server_params.authenticator;
+    let incoming_clients = server_params.incoming_clients.map(move |connection| connection.map(|connection| {
+        let authentication = super::authenticate(&mut authenticator, &connection);
+        (JsonFramed::new(connection), authentication)
+    }));
+
+    serve_authenticated(incoming_clients, Arc::new(server_params.authorizer), server_params.storage, server_params.spawn, server_params.logger.into()).await
+}
+
+async fn handle_client<Client, Store, Error>(mut client: Client, authentication: Authentication, shared: Shared, storage: Store, canceler: mpsc::UnboundedSender<()>, logger: slog::Logger) where
+    Client: Stream<Item=Result<Request, Error>> + Sink<Response, Error=Error> + Unpin,
+    Store: Storage {
+
+    let logger = super::client_logger(&authentication, logger);
+
+    let request = match client.next().await {
+        Some(Ok(request)) => request,
+        _ => return,
+    };
+
+    let (id, reply) = super::handshake_reply(request, authentication.is_ok(), &logger);
+    let capabilities = if let dscfg_proto::Reply::Welcome { ref capabilities, .. } = reply { Some(capabilities.clone()) } else { None };
+
+    if client.send(Response::Reply { id, reply }).await.is_err() {
+        return;
+    }
+
+    if let (Some(capabilities), Ok(identity)) = (capabilities, authentication) {
+        serve_client(client, capabilities, identity, shared, storage, canceler, logger).await;
+    }
+}
+
+async fn serve_client<Client, Store, Error>(mut client: Client, capabilities: Vec<String>, identity: Arc<dyn Identity>, shared: Shared, storage: Store, canceler: mpsc::UnboundedSender<()>, logger: slog::Logger) where
+    Client: Stream<Item=Result<Request, Error>> + Sink<Response, Error=Error> + Unpin,
+    Store: Storage {
+
+    debug!(logger, "client connected");
+
+    let (sender, receiver) = mpsc::unbounded();
+    let sender = Arc::new(sender);
+    let mut notifications = compatible_responses(receiver, capabilities.clone()).compat();
+
+    let mut handler = RequestHandler {
+        storage,
+        capabilities,
+        identity,
+        shared: shared.clone(),
+        canceler,
+        sender: sender.clone(),
+        logger: logger.clone(),
+    };
+
+    // Replies are sent through the same channel as notifications, so that notifications sent
+    // while handling a request are delivered before the reply.
+    loop {
+        let request = client.next().map(|request| match request {
+            Some(Ok(request)) => Some(request),
+            _ => None,
+        });
+
+        let response = match future::select(request, notifications.next()).await {
+            Either::Left((Some(request), _)) => {
+                handler.respond(request);
+                continue;
+            },
+            Either::Left((None, _)) => break,
+            Either::Right((Some(Ok(response)), _)) => response,
+            Either::Right(_) => panic!("sender terminated"),
+        };
+
+        if client.send(response).await.is_err() {
+            break;
+        }
+    }
+
+    // Replies to the last requests may still be in the channel.
+    while let Some(Some(Ok(response))) = notifications.next().now_or_never() {
+        if client.send(response).await.is_err() {
+            break;
+        }
+    }
+
+    shared.subscriptions.unsubscribe_all(&sender);
+    debug!(logger, "client disconnected");
+}
+
+/// Encodes and decodes messages using `dscfg_proto::framing`.
+///
+/// The encoding is the same as the one used by `dscfg_server::serve()`.
+struct JsonFramed<S> {
+    stream: S,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+}
+
+impl<S> JsonFramed<S> {
+    fn new(stream: S) -> Self {
+        JsonFramed {
+            stream,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> Stream for JsonFramed<S> {
+    type Item = io::Result<Request>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            match framing::decode_from(&mut this.read_buf) {
+                Ok(Some(message)) => return Poll::Ready(Some(Ok(message))),
+                Ok(None) => (),
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+
+            let filled = this.read_buf.len();
+            this.read_buf.resize(filled + READ_CHUNK_LEN, 0);
+
+            let read = match Pin::new(&mut this.stream).poll_read(cx, &mut this.read_buf[filled..]) {
+                Poll::Ready(Ok(read)) => read,
+                Poll::Ready(Err(err)) => {
+                    this.read_buf.truncate(filled);
+                    return Poll::Ready(Some(Err(err)));
+                },
+                Poll::Pending => {
+                    this.read_buf.truncate(filled);
+                    return Poll::Pending;
+                },
+            };
+            this.read_buf.truncate(filled + read);
+
+            if read == 0 {
+                return if this.read_buf.is_empty() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some(Err(io::ErrorKind::UnexpectedEof.into())))
+                };
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Sink<Response> for JsonFramed<S> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write_buf.len() < WRITE_BUFFER_LEN {
+            Poll::Ready(Ok(()))
+        } else {
+            self.poll_flush(cx)
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Response) -> io::Result<()> {
+        framing::encode_into(&message, &mut self.get_mut().write_buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while !this.write_buf.is_empty() {
+            match Pin::new(&mut this.stream).poll_write(cx, &this.write_buf) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => { this.write_buf.drain(..written); },
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => (),
+            other => return other,
+        }
+
+        Pin::new(&mut self.get_mut().stream).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonFramed;
+    use dscfg_proto::{framing, Operation, Request, MAX_MESSAGE_LEN};
+    use futures03::executor::block_on;
+    use futures03::io::Cursor;
+    use futures03::StreamExt;
+    use std::io;
+
+    fn request(id: u64) -> Vec<u8> {
+        framing::encode(&serde_json::json!({ "id": id, "operation": { "Get": { "key": "a" } } })).unwrap()
+    }
+
+    #[test]
+    fn decodes_requests() {
+        let mut input = request(1);
+        input.extend(request(2));
+        let mut framed = JsonFramed::new(Cursor::new(input));
+
+        for id in 1..=2 {
+            match block_on(framed.next()) {
+                Some(Ok(Request { id: received, operation: Operation::Get { key } })) => {
+                    assert_eq!(received, id);
+                    assert_eq!(key, "a");
+                },
+                _ => panic!("request wasn't decoded"),
+            }
+        }
+        assert!(block_on(framed.next()).is_none());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut input = request(1);
+        input.pop();
+        let mut framed = JsonFramed::new(Cursor::new(input));
+
+        match block_on(framed.next()) {
+            Some(Err(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => (),
+            _ => panic!("truncated frame wasn't refused"),
+        }
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut input = ((MAX_MESSAGE_LEN + 1) as u32).to_ne_bytes().to_vec();
+        input.extend(b"{}");
+        let mut framed = JsonFramed::new(Cursor::new(input));
+
+        match block_on(framed.next()) {
+            Some(Err(ref err)) if err.kind() == io::ErrorKind::InvalidData => (),
+            _ => panic!("oversized frame wasn't refused"),
+        }
+    }
+}
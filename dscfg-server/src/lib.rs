@@ -6,67 +6,285 @@ extern crate same;
 #[macro_use]
 extern crate slog;
 extern crate serde_json;
+#[cfg(feature = "asynchronous")]
+extern crate futures03;
 
 pub use dscfg_proto::json;
+pub use dscfg_proto::{Change, Permission};
 
 use futures::sync::mpsc::{self, UnboundedSender};
 use futures::{Future, Stream, Sink};
-use futures::future;
+use futures::{future, stream};
 use void::Void;
+use dscfg_proto::{Pattern, Revision};
+use std::borrow::Borrow;
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
 use same::RefCmp;
 use std::sync::RwLock;
+use std::fmt;
 use std::io;
 
+#[cfg(feature = "asynchronous")]
+pub mod asynchronous;
+pub mod acl;
+
+type Subscriber = RefCmp<Arc<mpsc::UnboundedSender<dscfg_proto::Response>>>;
+
+/// Subscribers indexed by patterns they subscribed with.
+///
+/// Prefix subscriptions as well as globs (grouped by their literal prefix) are looked up using
+/// every prefix of the changed key, so broadcasting doesn't have to scan all subscriptions.
+#[derive(Default)]
+struct SubscriptionIndex {
+    // Identities of the clients, used to check whether they may receive notifications.
+    identities: HashMap<Subscriber, Arc<dyn Identity>>,
+    keys: HashMap<String, HashSet<Subscriber>>,
+    prefixes: HashMap<String, HashSet<Subscriber>>,
+    globs: HashMap<String, HashMap<Pattern, HashSet<Subscriber>>>,
+}
+
+impl SubscriptionIndex {
+    fn insert(&mut self, client: Subscriber, pattern: Pattern) -> bool {
+        match pattern {
+            Pattern::Key(key) => self.keys.entry(key).or_default().insert(client),
+            Pattern::Prefix(prefix) => self.prefixes.entry(prefix).or_default().insert(client),
+            Pattern::Glob(_) => {
+                self.globs
+                    .entry(pattern.literal_prefix().to_owned())
+                    .or_default()
+                    .entry(pattern)
+                    .or_default()
+                    .insert(client)
+            },
+        }
+    }
+
+    fn remove(&mut self, client: &Subscriber, pattern: &Pattern) -> bool {
+        match pattern {
+            Pattern::Key(key) => remove_subscriber(&mut self.keys, key.as_str(), client),
+            Pattern::Prefix(prefix) => remove_subscriber(&mut self.prefixes, prefix.as_str(), client),
+            Pattern::Glob(_) => {
+                let literal_prefix = pattern.literal_prefix();
+                let (removed, is_empty) = match self.globs.get_mut(literal_prefix) {
+                    Some(globs) => (remove_subscriber(globs, pattern, client), globs.is_empty()),
+                    None => (false, false),
+                };
+
+                if is_empty {
+                    self.globs.remove(literal_prefix);
+                }
+
+                removed
+            },
+        }
+    }
+
+    fn remove_all(&mut self, client: &Subscriber) {
+        // Returns `true` if the set should be kept.
+        let remove = |subscribers: &mut HashSet<Subscriber>| {
+            subscribers.remove(client);
+            !subscribers.is_empty()
+        };
+
+        self.identities.remove(client);
+        self.keys.retain(|_, subscribers| remove(subscribers));
+        self.prefixes.retain(|_, subscribers| remove(subscribers));
+        self.globs.retain(|_, globs| {
+            globs.retain(|_, subscribers| remove(subscribers));
+            !globs.is_empty()
+        });
+    }
+
+    /// Returns all clients subscribed to the `key`, each of them exactly once.
+    // `RefCmp` compares the addresses, so the interior mutability of the sender doesn't matter.
+    #[allow(clippy::mutable_key_type)]
+    fn subscribers(&self, key: &str) -> HashSet<&Subscriber> {
+        let mut result = HashSet::new();
+
+        if let Some(subscribers) = self.keys.get(key) {
+            result.extend(subscribers);
+        }
+
+        let prefixes = key
+            .char_indices()
+            .map(|(pos, _)| &key[..pos])
+            .chain(std::iter::once(key));
+
+        for prefix in prefixes {
+            if let Some(subscribers) = self.prefixes.get(prefix) {
+                result.extend(subscribers);
+            }
+
+            if let Some(globs) = self.globs.get(prefix) {
+                for (glob, subscribers) in globs {
+                    if glob.matches(key) {
+                        result.extend(subscribers);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// Removes the client from the set at `key`, dropping the set if it's empty.
+fn remove_subscriber<K, Q>(subscriptions: &mut HashMap<K, HashSet<Subscriber>>, key: &Q, client: &Subscriber) -> bool where
+    K: Borrow<Q> + Hash + Eq,
+    Q: Hash + Eq + ?Sized {
+
+    let (removed, is_empty) = match subscriptions.get_mut(key) {
+        Some(subscribers) => (subscribers.remove(client), subscribers.is_empty()),
+        None => (false, false),
+    };
+
+    if is_empty {
+        subscriptions.remove(key);
+    }
+
+    removed
+}
+
 #[derive(Clone)]
-struct Subscriptions(Arc<RwLock<HashMap<String, HashSet<RefCmp<Arc<mpsc::UnboundedSender<dscfg_proto::Response>>>>>>>);
+struct Subscriptions {
+    index: Arc<RwLock<SubscriptionIndex>>,
+    authorizer: Arc<dyn Authorizer>,
+}
 
 impl Subscriptions {
-    fn new() -> Self {
-        Subscriptions(Default::default())
+    fn new(authorizer: Arc<dyn Authorizer>) -> Self {
+        Subscriptions {
+            index: Default::default(),
+            authorizer,
+        }
     }
 
-    fn subscribe(&self, client: &Arc<mpsc::UnboundedSender<dscfg_proto::Response>>, key: String) -> bool {
-        let mut subscriptions = self.0.write().unwrap();
+    fn subscribe(&self, client: &Arc<mpsc::UnboundedSender<dscfg_proto::Response>>, identity: &Arc<dyn Identity>, pattern: Pattern) -> bool {
+        let mut subscriptions = self.index.write().unwrap();
         let client = RefCmp(Arc::clone(client));
 
-        subscriptions.entry(key).or_insert_with(HashSet::new).insert(client)
+        subscriptions.identities.entry(RefCmp(Arc::clone(&client.0))).or_insert_with(|| Arc::clone(identity));
+        subscriptions.insert(client, pattern)
     }
 
-    fn unsubscribe(&self, client: &Arc<mpsc::UnboundedSender<dscfg_proto::Response>>, key: &str) -> bool {
-        let mut subscriptions = self.0.write().unwrap();
+    fn unsubscribe(&self, client: &Arc<mpsc::UnboundedSender<dscfg_proto::Response>>, pattern: &Pattern) -> bool {
+        let mut subscriptions = self.index.write().unwrap();
         let client = RefCmp(Arc::clone(client));
 
-        if let Some(subscriptions) = subscriptions.get_mut(key) {
-            subscriptions.remove(&client)
-        } else {
-            false
-        }
+        subscriptions.remove(&client, pattern)
     }
 
     fn unsubscribe_all(&self, client: &Arc<mpsc::UnboundedSender<dscfg_proto::Response>>) {
-        let mut subscriptions = self.0.write().unwrap();
+        let mut subscriptions = self.index.write().unwrap();
         let client = RefCmp(Arc::clone(client));
 
-        for (_, subscriptions) in &mut *subscriptions {
-            subscriptions.remove(&client);
-        }
+        subscriptions.remove_all(&client);
     }
 
-    fn broadcast(&self, key: String, value: json::Value) {
+    /// Sends a single `Changes` notification to each client subscribed to any of the changed keys.
+    #[allow(clippy::mutable_key_type)]
+    fn broadcast_changes(&self, changes: &[Change]) {
         use dscfg_proto::Response;
 
-        let subscriptions = self.0.read().unwrap();
+        let subscriptions = self.index.read().unwrap();
+        let mut notifications = HashMap::<_, Vec<_>>::new();
 
-        if let Some(subscriptions) = subscriptions.get(&key) {
-            for subscription in subscriptions {
-                subscription
-                    .unbounded_send(Response::Value { key: key.clone(), value: value.clone() })
-                    // This should never happen as the client unregisters itself.
-                    .unwrap()
+        for change in changes {
+            for subscription in self.allowed_subscribers(&subscriptions, change.key()) {
+                notifications.entry(subscription).or_default().push(change.clone());
             }
         }
+
+        for (subscription, changes) in notifications {
+            subscription
+                .unbounded_send(Response::Changes { changes })
+                // This should never happen as the client unregisters itself.
+                .unwrap()
+        }
+    }
+
+    fn broadcast(&self, key: &str, notification: dscfg_proto::Response) {
+        let subscriptions = self.index.read().unwrap();
+
+        for subscription in self.allowed_subscribers(&subscriptions, key) {
+            subscription
+                .unbounded_send(notification.clone())
+                // This should never happen as the client unregisters itself.
+                .unwrap()
+        }
+    }
+
+    /// Returns the clients subscribed to the `key` which may be notified about it.
+    ///
+    /// Clients subscribed with a single key were checked when subscribing, but prefixes and globs
+    /// may match keys the client isn't allowed to subscribe to.
+    fn allowed_subscribers<'a>(&self, subscriptions: &'a SubscriptionIndex, key: &str) -> impl Iterator<Item=&'a Subscriber> {
+        let authorizer = Arc::clone(&self.authorizer);
+        let key = key.to_owned();
+
+        subscriptions
+            .subscribers(&key)
+            .into_iter()
+            .filter(move |subscriber| match subscriptions.identities.get(*subscriber) {
+                Some(identity) => authorizer.is_allowed(&**identity, Permission::Subscribe, &key),
+                None => false,
+            })
+    }
+}
+
+/// Revisions of keys changed since the server started.
+///
+/// The mutex is also held while modifying the storage, so that compare-and-set operations are
+/// atomic and notifications are sent in the same order as revisions are assigned.
+#[derive(Clone)]
+struct Revisions(Arc<Mutex<RevisionMap>>);
+
+impl Revisions {
+    fn new() -> Self {
+        // Starting at current time makes it very unlikely that revisions repeat after restart.
+        let last = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs() * 1_000_000 + u64::from(time.subsec_micros()))
+            .unwrap_or(0);
+
+        let revisions = RevisionMap {
+            last,
+            keys: HashMap::new(),
+        };
+
+        Revisions(Arc::new(Mutex::new(revisions)))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RevisionMap> {
+        self.0.lock().unwrap()
+    }
+}
+
+struct RevisionMap {
+    last: Revision,
+    keys: HashMap<String, Revision>,
+}
+
+impl RevisionMap {
+    fn get(&self, key: &str) -> Revision {
+        self.keys.get(key).cloned().unwrap_or(0)
+    }
+
+    /// Assigns new revision to the key and returns it.
+    fn bump(&mut self, key: String) -> Revision {
+        self.bump_all(std::iter::once(key))
+    }
+
+    /// Assigns the same new revision to all keys and returns it.
+    fn bump_all<I: IntoIterator<Item=String>>(&mut self, keys: I) -> Revision {
+        self.last += 1;
+        for key in keys {
+            self.keys.insert(key, self.last);
+        }
+        self.last
     }
 }
 
@@ -90,9 +308,9 @@ impl IsFatalError for Void {
 /// of storing configuration data, you must implement this trait for your type.
 pub trait Storage {
     /// Error which may occur when attempting to write to the storage.
-    type SetError: IsFatalError;
+    type SetError: IsFatalError + fmt::Display;
     /// Error which may occur when attempting to read from the storage.
-    type GetError: IsFatalError;
+    type GetError: IsFatalError + fmt::Display;
 
     /// When this function is called, the implementor must store the given value for key in the
     /// storage or return error in case of failure.
@@ -100,6 +318,56 @@ pub trait Storage {
 
     /// The implementor must return the value at given key (if exists, `None` if not) or error if getting fails.
     fn get(&mut self, key: &str) -> Result<Option<json::Value>, Self::GetError>;
+
+    /// The implementor must return all existing keys starting with `prefix` or error if getting
+    /// fails.
+    ///
+    /// The order of the keys doesn't matter.
+    fn keys(&mut self, prefix: &str) -> Result<Vec<String>, Self::GetError>;
+
+    /// The implementor must return the values of all `keys` in the same order (`None` for keys
+    /// that don't exist) or error if getting fails.
+    ///
+    /// The default implementation calls `get()` for each key. Implementors may override it if
+    /// they can retrieve many values more efficiently.
+    fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<json::Value>>, Self::GetError> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// The implementor must return all existing keys starting with `prefix` along with their
+    /// values or error if getting fails.
+    ///
+    /// The order of the keys doesn't matter. The default implementation calls `keys()` and then
+    /// `get()` for each key.
+    fn get_prefix(&mut self, prefix: &str) -> Result<Vec<(String, json::Value)>, Self::GetError> {
+        let mut values = Vec::new();
+        for key in self.keys(prefix)? {
+            if let Some(value) = self.get(&key)? {
+                values.push((key, value));
+            }
+        }
+        Ok(values)
+    }
+
+    /// When this function is called, the implementor must remove the key from the storage.
+    ///
+    /// Returns `true` if the key existed, `false` otherwise, or error in case of failure.
+    fn remove(&mut self, key: &str) -> Result<bool, Self::SetError>;
+
+    /// When this function is called, the implementor must apply all changes in the given order
+    /// or return error in case of failure.
+    ///
+    /// The implementor should apply the changes atomically - either all of them or none. The
+    /// default implementation calls `set()` and `remove()` for each change, so it isn't atomic.
+    fn apply(&mut self, changes: Vec<Change>) -> Result<(), Self::SetError> {
+        for change in changes {
+            match change {
+                Change::Set { key, value } => self.set(key, value)?,
+                Change::Delete { key } => { self.remove(&key)?; },
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<T: Storage + ?Sized> Storage for Box<T> {
@@ -113,6 +381,26 @@ impl<T: Storage + ?Sized> Storage for Box<T> {
     fn get(&mut self, key: &str) -> Result<Option<json::Value>, Self::GetError> {
         (**self).get(key)
     }
+
+    fn keys(&mut self, prefix: &str) -> Result<Vec<String>, Self::GetError> {
+        (**self).keys(prefix)
+    }
+
+    fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<json::Value>>, Self::GetError> {
+        (**self).get_many(keys)
+    }
+
+    fn get_prefix(&mut self, prefix: &str) -> Result<Vec<(String, json::Value)>, Self::GetError> {
+        (**self).get_prefix(prefix)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, Self::SetError> {
+        (**self).remove(key)
+    }
+
+    fn apply(&mut self, changes: Vec<Change>) -> Result<(), Self::SetError> {
+        (**self).apply(changes)
+    }
 }
 
 /// Error that might occur when accessing `Store` synchronized with mutex.
@@ -132,6 +420,15 @@ impl<T: IsFatalError> IsFatalError for SyncOpResult<T> {
     }
 }
 
+impl<T: fmt::Display> fmt::Display for SyncOpResult<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncOpResult::Poisoned => write!(f, "the storage mutex was poisoned"),
+            SyncOpResult::Other(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
 impl<T> Storage for Arc<Mutex<T>> where T: Storage + ?Sized {
     type SetError = SyncOpResult<T::SetError>;
     type GetError = SyncOpResult<T::GetError>;
@@ -149,20 +446,65 @@ impl<T> Storage for Arc<Mutex<T>> where T: Storage + ?Sized {
             .get(key)
             .map_err(SyncOpResult::Other)
     }
+
+    fn keys(&mut self, prefix: &str) -> Result<Vec<String>, Self::GetError> {
+        self.lock()
+            .map_err(|_| SyncOpResult::Poisoned)?
+            .keys(prefix)
+            .map_err(SyncOpResult::Other)
+    }
+
+    fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<json::Value>>, Self::GetError> {
+        self.lock()
+            .map_err(|_| SyncOpResult::Poisoned)?
+            .get_many(keys)
+            .map_err(SyncOpResult::Other)
+    }
+
+    fn get_prefix(&mut self, prefix: &str) -> Result<Vec<(String, json::Value)>, Self::GetError> {
+        self.lock()
+            .map_err(|_| SyncOpResult::Poisoned)?
+            .get_prefix(prefix)
+            .map_err(SyncOpResult::Other)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, Self::SetError> {
+        self.lock()
+            .map_err(|_| SyncOpResult::Poisoned)?
+            .remove(key)
+            .map_err(SyncOpResult::Other)
+    }
+
+    fn apply(&mut self, changes: Vec<Change>) -> Result<(), Self::SetError> {
+        self.lock()
+            .map_err(|_| SyncOpResult::Poisoned)?
+            .apply(changes)
+            .map_err(SyncOpResult::Other)
+    }
 }
 
 /// Parameters the server needs to run
 ///
 /// Since there are several parameters the server needs, it's better
 /// to pass them as struct containing them.
-pub struct ServerParams<Incoming, Store, Executor, Logger> where 
+pub struct ServerParams<Incoming, Auth, Authz, Store, Executor, Logger> where 
     Incoming: Stream,
+    Auth: Authenticator<Incoming::Item>,
+    Authz: 'static + Authorizer,
     Store: Storage + Clone + Send,
-    Executor: future::Executor<Box<'static + Future<Item=(), Error=()> + Send>>,
+    Executor: future::Executor<Box<dyn 'static + Future<Item=(), Error=()> + Send>>,
     Logger: Into<slog::Logger> {
 
     /// Clients that are accepted.
     pub incoming_clients: Incoming,
+    /// Decides who the accepted clients are.
+    ///
+    /// Use `AllowAll` if the clients don't need to be authenticated.
+    pub authenticator: Auth,
+    /// Decides which keys the clients may access.
+    ///
+    /// Use `AllowAll` if all clients may access all keys.
+    pub authorizer: Authz,
     /// The implementation of configuration storage.
     pub storage: Store,
     /// Futures executor used for handling the clients. 
@@ -180,6 +522,113 @@ impl From<DiscardLogs> for slog::Logger {
     }
 }
 
+/// Identity of an authenticated client.
+///
+/// The identity is attached to logs related to the client, so the `Display` implementation should
+/// make it easy for humans to find out who the client is.
+pub trait Identity: fmt::Display + Send + Sync {
+    /// Returns `true` if the identity is described by the `principal`.
+    ///
+    /// Principals are strings used by policies (such as `acl::Acl`) to refer to clients. The
+    /// default implementation compares the `principal` with the string representation of the
+    /// identity, but identities containing several attributes may match various principals.
+    fn has_principal(&self, principal: &str) -> bool {
+        self.to_string() == principal
+    }
+}
+
+impl Identity for String {}
+
+/// Identity of clients accepted by `AllowAll`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Anonymous;
+
+impl fmt::Display for Anonymous {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "anonymous")
+    }
+}
+
+impl Identity for Anonymous {}
+
+/// Decides who the connected client is.
+///
+/// The authenticator is invoked for each accepted connection before the protocol handshake. If it
+/// fails, the handshake is refused with `PermissionDenied` error and the connection is closed.
+///
+/// It's implemented for closures, so simple policies don't need a dedicated type.
+pub trait Authenticator<Connection> {
+    /// Identity of authenticated clients.
+    type Identity: 'static + Identity;
+    /// Reason for rejecting the client, which is logged, but not sent to the client.
+    type Error: fmt::Display;
+
+    /// Returns the identity of the client connected using `connection` or an error if the client
+    /// should be rejected.
+    fn authenticate(&mut self, connection: &Connection) -> Result<Self::Identity, Self::Error>;
+}
+
+impl<Connection, F, I, E> Authenticator<Connection> for F where
+    F: FnMut(&Connection) -> Result<I, E>,
+    I: 'static + Identity,
+    E: fmt::Display {
+
+    type Identity = I;
+    type Error = E;
+
+    fn authenticate(&mut self, connection: &Connection) -> Result<Self::Identity, Self::Error> {
+        self(connection)
+    }
+}
+
+/// Authenticator accepting all clients as `Anonymous` and authorizer allowing all operations.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl<Connection> Authenticator<Connection> for AllowAll {
+    type Identity = Anonymous;
+    type Error = Void;
+
+    fn authenticate(&mut self, _connection: &Connection) -> Result<Self::Identity, Self::Error> {
+        Ok(Anonymous)
+    }
+}
+
+/// Decides which keys the clients may access.
+///
+/// The server checks the permissions of the client before performing each operation and refuses
+/// it with `Reply::PermissionDenied` if the client lacks a required permission. Operations
+/// accessing all keys with a prefix skip the keys the client isn't allowed to read. Similarly,
+/// clients aren't notified about changes of keys they aren't allowed to subscribe to.
+pub trait Authorizer: Send + Sync {
+    /// Returns `true` if the client with `identity` has the `permission` for the `key`.
+    fn is_allowed(&self, identity: &dyn Identity, permission: Permission, key: &str) -> bool;
+}
+
+impl<T: Authorizer + ?Sized> Authorizer for Box<T> {
+    fn is_allowed(&self, identity: &dyn Identity, permission: Permission, key: &str) -> bool {
+        (**self).is_allowed(identity, permission, key)
+    }
+}
+
+impl Authorizer for AllowAll {
+    fn is_allowed(&self, _identity: &dyn Identity, _permission: Permission, _key: &str) -> bool {
+        true
+    }
+}
+
+/// Result of authentication passed to the task handling the client.
+///
+/// The error is only logged, so it's converted to string.
+type Authentication = Result<Arc<dyn Identity>, String>;
+
+fn authenticate<Auth: Authenticator<Connection>, Connection>(authenticator: &mut Auth, connection: &Connection) -> Authentication {
+    match authenticator.authenticate(connection) {
+        Ok(identity) => Ok(Arc::new(identity)),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
 /// Error that might occur when attempting to accept a connection.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum HandlingError<E> {
@@ -189,177 +638,1282 @@ pub enum HandlingError<E> {
     Shutdown,
 }
 
-fn handle_client<Client, Store, Error>(client: Client, subscriptions: Subscriptions, mut storage: Store, canceler: UnboundedSender<()>) -> Box<'static + Future<Item=(), Error=()> + Send> where
-    Client: 'static + Stream<Item=dscfg_proto::Request, Error=Error> + Sink<SinkItem=dscfg_proto::Response, SinkError=Error> + Send,
-    Store: 'static + Storage + Send,
-    Error: 'static {
+/// Capabilities supported by this implementation of the server.
+const CAPABILITIES: &[&str] = &[
+    dscfg_proto::capability::DELETE,
+    dscfg_proto::capability::LIST,
+    dscfg_proto::capability::COMPARE_AND_SET,
+    dscfg_proto::capability::TRANSACTION,
+    dscfg_proto::capability::SUBSCRIBE_MANY,
+    dscfg_proto::capability::GET_MANY,
+    dscfg_proto::capability::PERMISSION_DENIED,
+    dscfg_proto::capability::NOT_FOUND,
+    dscfg_proto::capability::ERRORS,
+    dscfg_proto::capability::CHANGES,
+];
 
-    use dscfg_proto::{Request, Response};
+/// Performs the protocol handshake with the client.
+///
+/// The returned future resolves to `Some((client, capabilities))` if the client is compatible or to
+/// `None` if the connection should be closed. `capabilities` are those negotiated with the client.
+fn handshake<Client, Error>(client: Client, authenticated: bool, logger: slog::Logger) -> impl Future<Item=Option<(Client, Vec<String>)>, Error=()> where
+    Client: Stream<Item=dscfg_proto::Request, Error=Error> + Sink<SinkItem=dscfg_proto::Response, SinkError=Error> {
 
-    let (sender, receiver) = mpsc::unbounded();
-    let sender = Arc::new(sender);
-    let unsubscriber = subscriptions.clone();
-    let sender_unsubscribe = sender.clone();
+    use dscfg_proto::{Response, Reply};
 
-    let (sink, stream) = client.split();
+    client
+        .into_future()
+        .map_err(std::mem::drop)
+        .and_then(move |(request, client)| {
+            let (id, reply) = match request {
+                Some(request) => handshake_reply(request, authenticated, &logger),
+                None => return future::Either::A(future::ok(None)),
+            };
 
-    let stream = stream
-        .map(move |request| {
-            match request {
-                Request::Set { key, value } => {
-                    match storage.set(key.clone(), value.clone()) {
-                        Ok(_) => {
-                            subscriptions.broadcast(key, value);
-                            Response::OperationOk
-                        },
-                        Err(err) => {
-                            if err.is_fatal() {
-                                let _ = canceler.unbounded_send(());
-                            }
-                            Response::OperationFailed
-                        },
-                    }
-                },
-                Request::Get { key } => {
-                    match storage.get(&key) {
-                        Ok(Some(value)) => {
-                            Response::Value { key, value }
-                        },
-                        Ok(None) => Response::Value { key, value: json::Value::Null },
-                        Err(err) => {
-                            if err.is_fatal() {
-                                let _ = canceler.unbounded_send(());
-                            }
-                            Response::OperationFailed
-                        },
-                    }
-                },
-                Request::Subscribe { key, notify_now } => {
-                    if notify_now {
-                        match storage.get(&key) {
-                            Ok(Some(value)) => {
-                                let notification = Response::Value {
-                                    key: key.clone(),
-                                    value: value,
-                                };
-                                sender.unbounded_send(notification).unwrap();
-                            },
-                            Ok(None) => {
-                                let notification = Response::Value {
-                                    key: key.clone(),
-                                    value: json::Value::Null,
-                                };
-                                sender.unbounded_send(notification).unwrap();
-                            },
-                            Err(_) => return Response::OperationFailed,
-                        }
-                    }
+            let capabilities = if let Reply::Welcome { ref capabilities, .. } = reply { Some(capabilities.clone()) } else { None };
 
-                    if subscriptions.subscribe(&sender, key) {
-                        Response::OperationOk
-                    } else {
-                        Response::Ignored
-                    }
-                },
-                Request::Unsubscribe { key } => {
-                    if subscriptions.unsubscribe(&sender, &key) {
-                        Response::OperationOk
-                    } else {
-                        Response::Ignored
-                    }
-                }
-            }
+            future::Either::B(client
+                .send(Response::Reply { id, reply })
+                .map(move |client| capabilities.map(|capabilities| (client, capabilities)))
+                .map_err(std::mem::drop))
         })
-        .map_err(std::mem::drop);
+}
 
-    let receiver = receiver.map_err(|_| panic!("sender terminated"));
+/// Creates the reply to the first request of the client.
+///
+/// The client is compatible if and only if the reply is `Welcome`. Clients that failed
+/// authentication are refused, but only if they are compatible, so that they learn the reason.
+fn handshake_reply(request: dscfg_proto::Request, authenticated: bool, logger: &slog::Logger) -> (dscfg_proto::RequestId, dscfg_proto::Reply) {
+    use dscfg_proto::{Request, Operation, Reply, ErrorCode, PROTOCOL_VERSION};
 
-    let sink = sink.sink_map_err(std::mem::drop);
+    match request {
+        Request { id, operation: Operation::Hello { version, capabilities } } if version == PROTOCOL_VERSION => {
+            let capabilities = capabilities
+                .into_iter()
+                .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+                .collect::<Vec<_>>();
 
-    Box::new(stream
-        .select(receiver)
-        .forward(sink)
-        .map(std::mem::drop)
-        .map_err(std::mem::drop)
-        .then(move |result| { unsubscriber.unsubscribe_all(&sender_unsubscribe); result })
-    )
+            if authenticated {
+                (id, Reply::Welcome { version, capabilities })
+            } else {
+                let message = "authentication failed".to_owned();
+                (id, compatible_reply(Reply::Error { code: ErrorCode::PermissionDenied, message }, &capabilities))
+            }
+        },
+        Request { id, operation: Operation::Hello { version, .. } } => {
+            info!(logger, "rejecting client"; "cause" => "unsupported version", "version" => version);
+            (id, Reply::UnsupportedVersion { version: PROTOCOL_VERSION })
+        },
+        Request { id, .. } => {
+            info!(logger, "rejecting client"; "cause" => "missing handshake");
+            (id, Reply::UnsupportedVersion { version: PROTOCOL_VERSION })
+        },
+    }
 }
 
-/// Creates a server with custom client stream.
-///
-/// This may be used if one wants control over how the messages are serialized.
-/// If you want to use the default serialization (length-delimited json encoding),
-/// use `serve()` function.
-pub fn custom<Incoming, Store, Executor, Logger, CommError>(server_params: ServerParams<Incoming, Store, Executor, Logger>) -> impl Future<Item=(), Error=HandlingError<Incoming::Error>> where
-    Incoming: Stream,
-    Incoming::Item: 'static + Stream<Item=dscfg_proto::Request, Error=CommError> + Sink<SinkItem=dscfg_proto::Response, SinkError=CommError> + Send,
-    Store: 'static + Storage + Clone + Send,
-    Executor: future::Executor<Box<'static + Future<Item=(), Error=()> + Send>>,
-    Logger: Into<slog::Logger>,
-    CommError: 'static {
+/// Attaches the identity of the client to the logger or logs the reason for rejecting the client.
+fn client_logger(authentication: &Authentication, logger: slog::Logger) -> slog::Logger {
+    match authentication {
+        Ok(identity) => logger.new(o!("identity" => identity.to_string())),
+        Err(err) => {
+            info!(logger, "rejecting client"; "cause" => "authentication failed", "error" => err);
+            logger
+        },
+    }
+}
 
-    let logger = server_params.logger.into();
-    let executor = server_params.executor;
-    let storage = server_params.storage;
+fn handle_client<Client, Store, Error>(client: Client, authentication: Authentication, shared: Shared, storage: Store, canceler: UnboundedSender<()>, logger: slog::Logger) -> Box<dyn 'static + Future<Item=(), Error=()> + Send> where
+    Client: 'static + Stream<Item=dscfg_proto::Request, Error=Error> + Sink<SinkItem=dscfg_proto::Response, SinkError=Error> + Send,
+    Store: 'static + Storage + Send,
+    Error: 'static {
 
-    let subscriptions = Subscriptions::new();
-    let (canceler, cancelable) = mpsc::unbounded();
+    let logger = client_logger(&authentication, logger);
 
-    let cancelable = cancelable
-        .into_future()
-        .then(|_: Result<(Option<()>, futures::sync::mpsc::UnboundedReceiver<()>), _>| -> Result<(), HandlingError<Incoming::Error>> { Ok(()) });
+    Box::new(handshake(client, authentication.is_ok(), logger.clone())
+        .and_then(move |client| match (client, authentication) {
+            (Some((client, capabilities)), Ok(identity)) => future::Either::A(serve_client(client, capabilities, identity, shared, storage, canceler, logger)),
+            _ => future::Either::B(future::ok(())),
+        })
+    )
+}
 
-    let server = server_params.incoming_clients
-        .map_err(HandlingError::AcceptError)
-        .for_each(move |client| {
-            let client = handle_client(client, subscriptions.clone(), storage.clone(), canceler.clone());
+/// State shared by all clients.
+#[derive(Clone)]
+struct Shared {
+    subscriptions: Subscriptions,
+    revisions: Revisions,
+    authorizer: Arc<dyn Authorizer>,
+}
 
-            match executor.execute(client) {
-                Ok(_) => Ok(()),
-                Err(ref err) if err.kind() == future::ExecuteErrorKind::NoCapacity => {
-                    error!(logger, "failed to handle the client"; "cause" => "no capacity");
-                    // We'll just ignore this client - others might work
-                    Ok(())
-                },
-                Err(_) => {
-                    info!(logger, "shutting down");
-                    Err(HandlingError::Shutdown)
-                },
-            }
-        })
-        .select(cancelable)
-        .map(std::mem::drop)
-        .map_err(|(e, _)| e);
-    server
+/// Handles requests of a single client.
+struct RequestHandler<Store> {
+    storage: Store,
+    // Capabilities negotiated during handshake.
+    capabilities: Vec<String>,
+    identity: Arc<dyn Identity>,
+    shared: Shared,
+    canceler: UnboundedSender<()>,
+    // Used for sending notifications to this client.
+    sender: Arc<mpsc::UnboundedSender<dscfg_proto::Response>>,
+    // Contains the identity of the client.
+    logger: slog::Logger,
 }
 
-/// Creates default dscfg server.
-///
-/// This server uses length-delimited Json messages to transfer the data. Use `custom()` if you
-/// want to control encoding.
-pub fn serve<Incoming, Store, Executor, Logger>(server_params: ServerParams<Incoming, Store, Executor, Logger>) -> impl Future<Item=(), Error=HandlingError<Incoming::Error>> where
-    Incoming: Stream,
-    Incoming::Item: 'static + tokio_io::AsyncRead + tokio_io::AsyncWrite + Send,
-    Store: 'static + Storage + Clone + Send,
-    Executor: future::Executor<Box<'static + Future<Item=(), Error=()> + Send>>,
-    Logger: Into<slog::Logger> {
+impl<Store: Storage> RequestHandler<Store> {
+    fn handle(&mut self, request: dscfg_proto::Request) -> dscfg_proto::Response {
+        use dscfg_proto::{Request, Response};
 
-    let incoming_clients = server_params.incoming_clients.map(move |stream| {
-        // Workaround for unsuitable deprecation message - see
-        // https://github.com/tokio-rs/tokio/issues/680
+        let Request { id, operation } = request;
+        let reply = self.perform(operation);
+
+        Response::Reply { id, reply: compatible_reply(reply, &self.capabilities) }
+    }
+
+    fn perform(&mut self, operation: dscfg_proto::Operation) -> dscfg_proto::Reply {
+        use dscfg_proto::{Operation, Reply, ErrorCode};
+
+        if let Some(capability) = operation.required_capability() {
+            if !self.negotiated(capability) {
+                let message = format!("the capability {} wasn't negotiated", capability);
+                return Reply::Error { code: ErrorCode::Unsupported, message };
+            }
+        }
+
+        if let Err(reply) = self.authorize(&operation) {
+            return reply;
+        }
+
+        match operation {
+            Operation::Set { key, value } => self.set(key, value),
+            Operation::Get { key } => self.get(key),
+            Operation::GetMany { keys } => self.get_many(keys),
+            Operation::GetPrefix { prefix } => self.get_prefix(&prefix),
+            Operation::CompareAndSet { key, expected, value } => self.compare_and_set(key, expected, value),
+            Operation::Transaction { conditions, changes } => self.transaction(conditions, changes),
+            Operation::Delete { key } => self.delete(key),
+            Operation::List { prefix } => self.list(&prefix),
+            Operation::Subscribe { pattern, notify_now } => self.subscribe(pattern, notify_now),
+            Operation::SubscribeMany { patterns, notify_now } => self.subscribe_many(patterns, notify_now),
+            Operation::Unsubscribe { pattern } => {
+                if self.shared.subscriptions.unsubscribe(&self.sender, &pattern) {
+                    Reply::OperationOk
+                } else {
+                    Reply::Ignored
+                }
+            },
+            Operation::Hello { .. } => {
+                let message = "the handshake was already performed".to_owned();
+                Reply::Error { code: ErrorCode::ValidationFailed, message }
+            },
+        }
+    }
+
+    /// Returns `true` if the client negotiated the `capability` during handshake.
+    fn negotiated(&self, capability: &str) -> bool {
+        is_negotiated(&self.capabilities, capability)
+    }
+
+    /// Checks that the client has all permissions for the keys accessed by the `operation`.
+    ///
+    /// Operations accessing keys by prefix or pattern are allowed, but they have to skip the keys
+    /// the client may not access.
+    fn authorize(&self, operation: &dscfg_proto::Operation) -> Result<(), dscfg_proto::Reply> {
+        use dscfg_proto::{Operation, Condition};
+
+        let mut required = Vec::new();
+        match operation {
+            Operation::Set { key, .. } |
+            Operation::Delete { key } => required.push((Permission::Write, key)),
+            Operation::Get { key } => required.push((Permission::Read, key)),
+            Operation::GetMany { keys } => required.extend(keys.iter().map(|key| (Permission::Read, key))),
+            // The reply to failed condition contains the current value.
+            Operation::CompareAndSet { key, .. } => required.extend(&[(Permission::Read, key), (Permission::Write, key)]),
+            Operation::Transaction { conditions, changes } => {
+                required.extend(conditions.iter().map(|Condition { key, .. }| (Permission::Read, key)));
+                required.extend(changes.iter().map(|change| match change {
+                    Change::Set { key, .. } | Change::Delete { key } => (Permission::Write, key),
+                }));
+            },
+            Operation::Subscribe { pattern: Pattern::Key(key), .. } => required.push((Permission::Subscribe, key)),
+            Operation::SubscribeMany { patterns, .. } => required.extend(patterns.iter().filter_map(|pattern| match pattern {
+                Pattern::Key(key) => Some((Permission::Subscribe, key)),
+                _ => None,
+            })),
+            Operation::Hello { .. } |
+            Operation::GetPrefix { .. } |
+            Operation::List { .. } |
+            Operation::Subscribe { .. } |
+            Operation::Unsubscribe { .. } => (),
+        }
+
+        for (permission, key) in required {
+            if !self.is_allowed(permission, key) {
+                info!(self.logger, "permission denied"; "permission" => %permission, "key" => key);
+                return Err(dscfg_proto::Reply::PermissionDenied { key: key.clone(), permission });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_allowed(&self, permission: Permission, key: &str) -> bool {
+        self.shared.authorizer.is_allowed(&*self.identity, permission, key)
+    }
+
+    /// Removes the keys the client may not subscribe to from current `values`.
+    fn retain_subscribable(&self, values: &mut Vec<(String, Option<json::Value>)>) {
+        values.retain(|(key, _)| self.is_allowed(Permission::Subscribe, key));
+    }
+
+    /// Replaces missing `values` with `null` if the client doesn't distinguish them.
+    fn fill_missing(&self, values: &mut [(String, Option<json::Value>)]) {
+        if !self.negotiated(dscfg_proto::capability::NOT_FOUND) {
+            for (_, value) in values {
+                value.get_or_insert(json::Value::Null);
+            }
+        }
+    }
+
+    /// Handles the request and sends the response to the client.
+    fn respond(&mut self, request: dscfg_proto::Request) {
+        let response = self.handle(request);
+        self.sender
+            .unbounded_send(response)
+            // This should never happen as the receiver lives as long as the handler.
+            .unwrap()
+    }
+
+    /// Creates reply to failed storage operation, stopping the server if the error is fatal.
+    fn failed<E: IsFatalError + fmt::Display>(&self, error: E) -> dscfg_proto::Reply {
+        error!(self.logger, "storage operation failed"; "error" => %error, "fatal" => error.is_fatal());
+
+        if error.is_fatal() {
+            let _ = self.canceler.unbounded_send(());
+        }
+
+        dscfg_proto::Reply::Error {
+            code: dscfg_proto::ErrorCode::StorageFailure,
+            message: error.to_string(),
+        }
+    }
+
+    fn set(&mut self, key: String, value: json::Value) -> dscfg_proto::Reply {
+        use dscfg_proto::{Response, Reply};
+
+        let mut revisions = self.shared.revisions.lock();
+
+        match self.storage.set(key.clone(), value.clone()) {
+            Ok(_) => {
+                revisions.bump(key.clone());
+                self.shared.subscriptions.broadcast(&key, Response::Notification { key: key.clone(), value });
+                Reply::OperationOk
+            },
+            Err(err) => self.failed(err),
+        }
+    }
+
+    fn get(&mut self, key: String) -> dscfg_proto::Reply {
+        use dscfg_proto::{capability, Reply};
+
+        let revisions = self.shared.revisions.lock();
+
+        let revision = revisions.get(&key);
+
+        match self.storage.get(&key) {
+            Ok(Some(value)) => Reply::Value { key, value, revision },
+            Ok(None) if self.negotiated(capability::NOT_FOUND) => Reply::NotFound { key, revision },
+            Ok(None) => Reply::Value { key, value: json::Value::Null, revision },
+            Err(err) => self.failed(err),
+        }
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> dscfg_proto::Reply {
+        use dscfg_proto::{Entry, Reply};
+
+        let revisions = self.shared.revisions.lock();
+
+        match self.storage.get_many(&keys) {
+            Ok(found) => {
+                let values = keys
+                    .into_iter()
+                    .zip(found)
+                    .map(|(key, value)| match value {
+                        Some(value) => {
+                            let revision = revisions.get(&key);
+                            Entry::Present { key, value, revision }
+                        },
+                        None => Entry::Absent { key },
+                    })
+                    .collect();
+
+                Reply::Values { values }
+            },
+            Err(err) => self.failed(err),
+        }
+    }
+
+    fn get_prefix(&mut self, prefix: &str) -> dscfg_proto::Reply {
+        use dscfg_proto::{Entry, Reply};
+
+        let revisions = self.shared.revisions.lock();
+
+        match self.storage.get_prefix(prefix) {
+            Ok(mut found) => {
+                found.retain(|(key, _)| self.is_allowed(Permission::Read, key));
+                found.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+                let values = found
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let revision = revisions.get(&key);
+                        Entry::Present { key, value, revision }
+                    })
+                    .collect();
+
+                Reply::Values { values }
+            },
+            Err(err) => self.failed(err),
+        }
+    }
+
+    fn compare_and_set(&mut self, key: String, expected: dscfg_proto::Expected, value: json::Value) -> dscfg_proto::Reply {
+        use dscfg_proto::{Response, Reply};
+
+        // Holding the lock prevents others from changing the value between the check and the
+        // update.
+        let mut revisions = self.shared.revisions.lock();
+        let not_found = self.negotiated(dscfg_proto::capability::NOT_FOUND);
+
+        match check_condition(&mut self.storage, &revisions, key.clone(), expected, not_found) {
+            Ok(None) => (),
+            Ok(Some(conflict)) => return conflict,
+            Err(err) => return self.failed(err),
+        }
+
+        match self.storage.set(key.clone(), value.clone()) {
+            Ok(_) => {
+                let revision = revisions.bump(key.clone());
+                self.shared.subscriptions.broadcast(&key, Response::Notification { key: key.clone(), value });
+                Reply::Updated { revision }
+            },
+            Err(err) => self.failed(err),
+        }
+    }
+
+    fn transaction(&mut self, conditions: Vec<dscfg_proto::Condition>, mut changes: Vec<Change>) -> dscfg_proto::Reply {
+        use dscfg_proto::{Condition, Reply};
+
+        let mut revisions = self.shared.revisions.lock();
+        let not_found = self.negotiated(dscfg_proto::capability::NOT_FOUND);
+
+        for Condition { key, expected } in conditions {
+            match check_condition(&mut self.storage, &revisions, key, expected, not_found) {
+                Ok(None) => (),
+                Ok(Some(conflict)) => return conflict,
+                Err(err) => return self.failed(err),
+            }
+        }
+
+        // Deleting keys that don't exist is a no-op, so subscribers shouldn't be notified. Earlier
+        // changes in the transaction may create or delete the keys, so they are replayed.
+        let mut exists = HashMap::<&str, bool>::new();
+        let mut effective = Vec::with_capacity(changes.len());
+        for change in &changes {
+            let key = change.key();
+            let existed = match exists.get(key) {
+                Some(existed) => *existed,
+                None => match self.storage.get(key) {
+                    Ok(value) => value.is_some(),
+                    Err(err) => return self.failed(err),
+                },
+            };
+
+            match change {
+                Change::Set { .. } => {
+                    exists.insert(key, true);
+                    effective.push(true);
+                },
+                Change::Delete { .. } => {
+                    exists.insert(key, false);
+                    effective.push(existed);
+                },
+            }
+        }
+        let mut effective = effective.into_iter();
+        changes.retain(|_| effective.next().unwrap_or(true));
+
+        // Nothing changes, so no key gets a new revision.
+        if changes.is_empty() {
+            return Reply::Updated { revision: revisions.last };
+        }
+
+        match self.storage.apply(changes.clone()) {
+            Ok(_) => {
+                let revision = revisions.bump_all(changes.iter().map(|change| change.key().to_owned()));
+                self.shared.subscriptions.broadcast_changes(&changes);
+                Reply::Updated { revision }
+            },
+            Err(err) => self.failed(err),
+        }
+    }
+
+    fn delete(&mut self, key: String) -> dscfg_proto::Reply {
+        use dscfg_proto::{Response, Reply};
+
+        let mut revisions = self.shared.revisions.lock();
+
+        match self.storage.remove(&key) {
+            Ok(true) => {
+                revisions.bump(key.clone());
+                self.shared.subscriptions.broadcast(&key, Response::Deleted { key: key.clone() });
+                Reply::OperationOk
+            },
+            Ok(false) => Reply::Ignored,
+            Err(err) => self.failed(err),
+        }
+    }
+
+    fn list(&mut self, prefix: &str) -> dscfg_proto::Reply {
+        use dscfg_proto::Reply;
+
+        match self.storage.keys(prefix) {
+            Ok(mut keys) => {
+                keys.retain(|key| self.is_allowed(Permission::Read, key));
+                keys.sort();
+                Reply::Keys { keys }
+            },
+            Err(err) => self.failed(err),
+        }
+    }
+
+    fn subscribe(&mut self, pattern: Pattern, notify_now: bool) -> dscfg_proto::Reply {
+        use dscfg_proto::Reply;
+
+        // Prevents changes between sending current values and subscribing.
+        let _revisions = self.shared.revisions.lock();
+
+        if notify_now {
+            match current_values(&mut self.storage, &pattern) {
+                Ok(mut values) => {
+                    self.retain_subscribable(&mut values);
+                    self.fill_missing(&mut values);
+                    notify_current(values, &self.sender)
+                },
+                Err(err) => return self.failed(err),
+            }
+        }
+
+        if self.shared.subscriptions.subscribe(&self.sender, &self.identity, pattern) {
+            Reply::OperationOk
+        } else {
+            Reply::Ignored
+        }
+    }
+
+    fn subscribe_many(&mut self, patterns: Vec<Pattern>, notify_now: bool) -> dscfg_proto::Reply {
+        use dscfg_proto::{Response, Reply};
+
+        let _revisions = self.shared.revisions.lock();
+
+        if notify_now {
+            let mut current = HashMap::new();
+            for pattern in &patterns {
+                match current_values(&mut self.storage, pattern) {
+                    Ok(mut values) => {
+                        self.retain_subscribable(&mut values);
+                        self.fill_missing(&mut values);
+                        current.extend(values)
+                    },
+                    Err(err) => return self.failed(err),
+                }
+            }
+
+            if !current.is_empty() {
+                let mut changes = current
+                    .into_iter()
+                    .map(|(key, value)| match value {
+                        Some(value) => Change::Set { key, value },
+                        None => Change::Delete { key },
+                    })
+                    .collect::<Vec<_>>();
+                changes.sort_by(|a, b| a.key().cmp(b.key()));
+
+                self.sender
+                    .unbounded_send(Response::Changes { changes })
+                    // This should never happen as the receiver lives as long as the handler.
+                    .unwrap();
+            }
+        }
+
+        let mut subscribed = false;
+        for pattern in patterns {
+            subscribed |= self.shared.subscriptions.subscribe(&self.sender, &self.identity, pattern);
+        }
+
+        if subscribed {
+            Reply::OperationOk
+        } else {
+            Reply::Ignored
+        }
+    }
+}
+
+/// Returns `true` if the `capability` is one of the negotiated `capabilities`.
+fn is_negotiated(capabilities: &[String], capability: &str) -> bool {
+    capabilities.iter().any(|negotiated| negotiated == capability)
+}
+
+/// Converts the `reply` to one understood by the client with `capabilities`.
+fn compatible_reply(reply: dscfg_proto::Reply, capabilities: &[String]) -> dscfg_proto::Reply {
+    use dscfg_proto::{capability, Reply, ErrorCode};
+
+    let reply = match reply {
+        Reply::PermissionDenied { key, permission } if !is_negotiated(capabilities, capability::PERMISSION_DENIED) => {
+            let message = format!("permission to {} {} denied", permission, key);
+            Reply::Error { code: ErrorCode::PermissionDenied, message }
+        },
+        reply => reply,
+    };
+
+    match reply {
+        Reply::Error { .. } if !is_negotiated(capabilities, capability::ERRORS) => Reply::OperationFailed,
+        reply => reply,
+    }
+}
+
+/// Converts the responses sent through the channel of the client to ones it understands.
+///
+/// Notifications are sent by handlers of other clients too, so they can't be converted by
+/// the sender.
+fn compatible_responses(receiver: mpsc::UnboundedReceiver<dscfg_proto::Response>, capabilities: Vec<String>) -> impl Stream<Item=dscfg_proto::Response, Error=()> {
+    use dscfg_proto::{capability, Response};
+
+    let split_changes = !is_negotiated(&capabilities, capability::CHANGES);
+
+    receiver
+        .map(move |response| {
+            let responses = match response {
+                Response::Changes { changes } if split_changes => changes
+                    .into_iter()
+                    .map(|change| match change {
+                        Change::Set { key, value } => Response::Notification { key, value },
+                        Change::Delete { key } => Response::Deleted { key },
+                    })
+                    .collect(),
+                response => vec![response],
+            };
+
+            stream::iter_ok(responses)
+        })
+        .flatten()
+}
+
+/// Checks whether the `expected` condition holds for the `key`.
+///
+/// Returns `Conflict` reply if it doesn't or `NotFound` if it doesn't because the key doesn't
+/// exist. If `not_found` is `false`, missing keys are reported as `Conflict` with `null` value.
+fn check_condition<Store: Storage>(storage: &mut Store, revisions: &RevisionMap, key: String, expected: dscfg_proto::Expected, not_found: bool) -> Result<Option<dscfg_proto::Reply>, Store::GetError> {
+    use dscfg_proto::{Expected, Reply};
+
+    let revision = revisions.get(&key);
+    let current = storage.get(&key)?;
+
+    let matches = match expected {
+        Expected::Revision(expected) => expected == revision,
+        Expected::Value(expected) => current.as_ref() == Some(&expected),
+        Expected::Absent => current.is_none(),
+    };
+
+    match current {
+        _ if matches => Ok(None),
+        Some(value) => Ok(Some(Reply::Conflict { key, value, revision })),
+        None if not_found => Ok(Some(Reply::NotFound { key, revision })),
+        None => Ok(Some(Reply::Conflict { key, value: json::Value::Null, revision })),
+    }
+}
+
+/// Sends current `values` of keys to the client.
+fn notify_current(values: Vec<(String, Option<json::Value>)>, client: &mpsc::UnboundedSender<dscfg_proto::Response>) {
+    use dscfg_proto::Response;
+
+    for (key, value) in values {
+        let notification = match value {
+            Some(value) => Response::Notification { key, value },
+            None => Response::Deleted { key },
+        };
+        client.unbounded_send(notification).unwrap();
+    }
+}
+
+/// Returns current values of the keys matching the `pattern` sorted by keys.
+///
+/// A single key is always returned, with `None` value if it doesn't exist. Other patterns only
+/// return existing keys.
+fn current_values<Store: Storage>(storage: &mut Store, pattern: &Pattern) -> Result<Vec<(String, Option<json::Value>)>, Store::GetError> {
+    if let Pattern::Key(key) = pattern {
+        let value = storage.get(key)?;
+        return Ok(vec![(key.clone(), value)]);
+    }
+
+    let mut values = storage.get_prefix(pattern.literal_prefix())?;
+    values.retain(|(key, _)| pattern.matches(key));
+    values.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    Ok(values.into_iter().map(|(key, value)| (key, Some(value))).collect())
+}
+
+fn serve_client<Client, Store, Error>(client: Client, capabilities: Vec<String>, identity: Arc<dyn Identity>, shared: Shared, storage: Store, canceler: UnboundedSender<()>, logger: slog::Logger) -> Box<dyn 'static + Future<Item=(), Error=()> + Send> where
+    Client: 'static + Stream<Item=dscfg_proto::Request, Error=Error> + Sink<SinkItem=dscfg_proto::Response, SinkError=Error> + Send,
+    Store: 'static + Storage + Send,
+    Error: 'static {
+
+    let (sender, receiver) = mpsc::unbounded();
+    let sender = Arc::new(sender);
+    let unsubscriber = shared.subscriptions.clone();
+    let sender_unsubscribe = sender.clone();
+
+    debug!(logger, "client connected");
+
+    let receiver = compatible_responses(receiver, capabilities.clone());
+
+    let mut handler = RequestHandler {
+        storage,
+        capabilities,
+        identity,
+        shared,
+        canceler,
+        sender,
+        logger: logger.clone(),
+    };
+
+    let (sink, stream) = client.split();
+
+    // Replies are sent through the same channel as notifications, so that notifications sent
+    // while handling a request are delivered before the reply.
+    let stream = stream
+        .filter_map(move |request| {
+            handler.respond(request);
+            None
+        })
+        .map_err(std::mem::drop);
+
+    let receiver = receiver.map_err(|_| panic!("sender terminated"));
+
+    let sink = sink.sink_map_err(std::mem::drop);
+
+    Box::new(stream
+        .select(receiver)
+        .forward(sink)
+        .map(std::mem::drop)
+        .map_err(std::mem::drop)
+        .then(move |result| {
+            unsubscriber.unsubscribe_all(&sender_unsubscribe);
+            debug!(logger, "client disconnected");
+            result
+        })
+    )
+}
+
+/// Creates a server with custom client stream.
+///
+/// This may be used if one wants control over how the messages are serialized.
+/// If you want to use the default serialization (length-delimited json encoding),
+/// use `serve()` function.
+pub fn custom<Incoming, Auth, Authz, Store, Executor, Logger, CommError>(server_params: ServerParams<Incoming, Auth, Authz, Store, Executor, Logger>) -> impl Future<Item=(), Error=HandlingError<Incoming::Error>> where
+    Incoming: Stream,
+    Incoming::Item: 'static + Stream<Item=dscfg_proto::Request, Error=CommError> + Sink<SinkItem=dscfg_proto::Response, SinkError=CommError> + Send,
+    Auth: Authenticator<Incoming::Item>,
+    Authz: 'static + Authorizer,
+    Store: 'static + Storage + Clone + Send,
+    Executor: future::Executor<Box<dyn 'static + Future<Item=(), Error=()> + Send>>,
+    Logger: Into<slog::Logger>,
+    CommError: 'static {
+
+    let mut authenticator = server_params.authenticator;
+    let incoming_clients = server_params.incoming_clients.map(move |client| {
+        let authentication = authenticate(&mut authenticator, &client);
+        (client, authentication)
+    });
+
+    serve_authenticated(incoming_clients, Arc::new(server_params.authorizer), server_params.storage, server_params.executor, server_params.logger.into())
+}
+
+/// Runs the server with clients that were already authenticated.
+fn serve_authenticated<Incoming, Client, Store, Executor, CommError>(incoming_clients: Incoming, authorizer: Arc<dyn Authorizer>, storage: Store, executor: Executor, logger: slog::Logger) -> impl Future<Item=(), Error=HandlingError<Incoming::Error>> where
+    Incoming: Stream<Item=(Client, Authentication)>,
+    Client: 'static + Stream<Item=dscfg_proto::Request, Error=CommError> + Sink<SinkItem=dscfg_proto::Response, SinkError=CommError> + Send,
+    Store: 'static + Storage + Clone + Send,
+    Executor: future::Executor<Box<dyn 'static + Future<Item=(), Error=()> + Send>>,
+    CommError: 'static {
+
+    let shared = Shared {
+        subscriptions: Subscriptions::new(Arc::clone(&authorizer)),
+        revisions: Revisions::new(),
+        authorizer,
+    };
+    let (canceler, cancelable) = mpsc::unbounded();
+
+    let cancelable = cancelable
+        .into_future()
+        .then(|_: Result<(Option<()>, futures::sync::mpsc::UnboundedReceiver<()>), _>| -> Result<(), HandlingError<Incoming::Error>> { Ok(()) });
+
+    let server = incoming_clients
+        .map_err(HandlingError::AcceptError)
+        .for_each(move |(client, authentication)| {
+            let client = handle_client(client, authentication, shared.clone(), storage.clone(), canceler.clone(), logger.clone());
+
+            match executor.execute(client) {
+                Ok(_) => Ok(()),
+                Err(ref err) if err.kind() == future::ExecuteErrorKind::NoCapacity => {
+                    error!(logger, "failed to handle the client"; "cause" => "no capacity");
+                    // We'll just ignore this client - others might work
+                    Ok(())
+                },
+                Err(_) => {
+                    info!(logger, "shutting down");
+                    Err(HandlingError::Shutdown)
+                },
+            }
+        })
+        .select(cancelable)
+        .map(std::mem::drop)
+        .map_err(|(e, _)| e);
+    server
+}
+
+/// Creates default dscfg server.
+///
+/// This server uses length-delimited Json messages to transfer the data. Use `custom()` if you
+/// want to control encoding.
+pub fn serve<Incoming, Auth, Authz, Store, Executor, Logger>(server_params: ServerParams<Incoming, Auth, Authz, Store, Executor, Logger>) -> impl Future<Item=(), Error=HandlingError<Incoming::Error>> where
+    Incoming: Stream,
+    Incoming::Item: 'static + tokio_io::AsyncRead + tokio_io::AsyncWrite + Send,
+    Auth: Authenticator<Incoming::Item>,
+    Authz: 'static + Authorizer,
+    Store: 'static + Storage + Clone + Send,
+    Executor: future::Executor<Box<dyn 'static + Future<Item=(), Error=()> + Send>>,
+    Logger: Into<slog::Logger> {
+
+    let mut authenticator = server_params.authenticator;
+    // The connection is authenticated before it's wrapped, so that the authenticator can access it.
+    let incoming_clients = server_params.incoming_clients.map(move |stream| {
+        let authentication = authenticate(&mut authenticator, &stream);
+
+        // Workaround for unsuitable deprecation message - see
+        // https://github.com/tokio-rs/tokio/issues/680
         #[allow(deprecated)]
-        tokio_io::codec::length_delimited::Builder::new()
+        let client = tokio_io::codec::length_delimited::Builder::new()
             .native_endian()
+            .max_frame_length(dscfg_proto::MAX_MESSAGE_LEN)
             .new_framed(stream)
             .and_then(|message| serde_json::from_slice(&message).map_err(Into::into))
-            .with(|message| serde_json::to_vec(&message).map_err(io::Error::from))
+            .with(|message| serde_json::to_vec(&message).map_err(io::Error::from));
+
+        (client, authentication)
     });
 
-    let params = ServerParams {
-        incoming_clients,
-        storage: server_params.storage,
-        executor: server_params.executor,
-        logger: server_params.logger
-    };
-    custom(params)
+    serve_authenticated(incoming_clients, Arc::new(server_params.authorizer), server_params.storage, server_params.executor, server_params.logger.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dscfg_proto::{Operation, Reply, Request, Response};
+
+    /// Storage keeping the values in memory.
+    #[derive(Default)]
+    struct Memory(HashMap<String, json::Value>);
+
+    impl Storage for Memory {
+        type SetError = Void;
+        type GetError = Void;
+
+        fn set(&mut self, key: String, value: json::Value) -> Result<(), Self::SetError> {
+            self.0.insert(key, value);
+            Ok(())
+        }
+
+        fn get(&mut self, key: &str) -> Result<Option<json::Value>, Self::GetError> {
+            Ok(self.0.get(key).cloned())
+        }
+
+        fn keys(&mut self, prefix: &str) -> Result<Vec<String>, Self::GetError> {
+            Ok(self.0.keys().filter(|key| key.starts_with(prefix)).cloned().collect())
+        }
+
+        fn remove(&mut self, key: &str) -> Result<bool, Self::SetError> {
+            Ok(self.0.remove(key).is_some())
+        }
+    }
+
+    /// Creates a handler of a client which negotiated all capabilities.
+    fn handler(values: &[(&str, json::Value)]) -> (RequestHandler<Memory>, mpsc::UnboundedReceiver<Response>) {
+        handler_with_capabilities(values, CAPABILITIES)
+    }
+
+    fn handler_with_capabilities(values: &[(&str, json::Value)], capabilities: &[&str]) -> (RequestHandler<Memory>, mpsc::UnboundedReceiver<Response>) {
+        client(&shared(AllowAll), values, capabilities, Arc::new(Anonymous))
+    }
+
+    fn shared<A: Authorizer + 'static>(authorizer: A) -> Shared {
+        let authorizer: Arc<dyn Authorizer> = Arc::new(authorizer);
+
+        Shared {
+            subscriptions: Subscriptions::new(Arc::clone(&authorizer)),
+            revisions: Revisions::new(),
+            authorizer,
+        }
+    }
+
+    /// Creates a handler of a client with `identity` connected to the server with `shared` state.
+    fn client(shared: &Shared, values: &[(&str, json::Value)], capabilities: &[&str], identity: Arc<dyn Identity>) -> (RequestHandler<Memory>, mpsc::UnboundedReceiver<Response>) {
+        let storage = Memory(values.iter().map(|(key, value)| (key.to_string(), value.clone())).collect());
+        let (sender, receiver) = mpsc::unbounded();
+        let (canceler, _) = mpsc::unbounded();
+
+        let handler = RequestHandler {
+            storage,
+            capabilities: capabilities.iter().map(|capability| String::from(*capability)).collect(),
+            identity,
+            shared: shared.clone(),
+            canceler,
+            sender: Arc::new(sender),
+            logger: DiscardLogs.into(),
+        };
+
+        (handler, receiver)
+    }
+
+    /// Shared state of a server allowing anonymous clients to access only keys starting with
+    /// `public`.
+    fn restricted() -> Shared {
+        let policy = "
+            read,write,subscribe  public*  *
+            read,write,subscribe  *        admin
+        ";
+
+        shared(policy.parse::<acl::Acl>().unwrap())
+    }
+
+    fn reply(handler: &mut RequestHandler<Memory>, operation: Operation) -> Reply {
+        match handler.handle(Request { id: 0, operation }) {
+            Response::Reply { reply, .. } => reply,
+            _ => panic!("the response isn't a reply"),
+        }
+    }
+
+    /// Returns the changes the client was notified about.
+    fn notified(handler: RequestHandler<Memory>, receiver: mpsc::UnboundedReceiver<Response>) -> Vec<Change> {
+        // Dropping the handler drops all senders, so the receiver ends.
+        std::mem::drop(handler);
+
+        receiver
+            .wait()
+            .flat_map(|response| match response.unwrap() {
+                Response::Notification { key, value } => vec![Change::Set { key, value }],
+                Response::Deleted { key } => vec![Change::Delete { key }],
+                Response::Changes { changes } => changes,
+                Response::Reply { .. } => panic!("unexpected reply"),
+            })
+            .collect()
+    }
+
+    fn transaction(handler: &mut RequestHandler<Memory>, changes: Vec<Change>) {
+        match reply(handler, Operation::Transaction { conditions: Vec::new(), changes }) {
+            Reply::Updated { .. } => (),
+            _ => panic!("the transaction failed"),
+        }
+    }
+
+    fn subscribe(handler: &mut RequestHandler<Memory>, key: &str) {
+        let pattern = Pattern::Key(key.to_owned());
+        match reply(handler, Operation::Subscribe { pattern, notify_now: false }) {
+            Reply::OperationOk => (),
+            _ => panic!("subscribing failed"),
+        }
+    }
+
+    #[test]
+    fn changes_split_without_capability() {
+        let (mut handler, receiver) = handler(&[("b", json::Value::from(1))]);
+        subscribe(&mut handler, "a");
+        subscribe(&mut handler, "b");
+
+        let changes = vec![
+            Change::Set { key: "a".to_owned(), value: json::Value::from(2) },
+            Change::Delete { key: "b".to_owned() },
+        ];
+        transaction(&mut handler, changes);
+        std::mem::drop(handler);
+
+        let responses = compatible_responses(receiver, Vec::new()).wait().collect::<Result<Vec<_>, _>>().unwrap();
+        match &responses[..] {
+            [Response::Notification { key: a, value }, Response::Deleted { key: b }] => {
+                assert_eq!(a, "a");
+                assert_eq!(*value, json::Value::from(2));
+                assert_eq!(b, "b");
+            },
+            _ => panic!("changes weren't split"),
+        }
+    }
+
+    #[test]
+    fn compatible_replies() {
+        use dscfg_proto::{capability, ErrorCode};
+
+        let permission_denied = || Reply::PermissionDenied { key: "a".to_owned(), permission: Permission::Write };
+        let errors = [capability::ERRORS.to_owned()];
+
+        match compatible_reply(permission_denied(), &errors) {
+            Reply::Error { code: ErrorCode::PermissionDenied, message } => assert_eq!(message, "permission to write a denied"),
+            _ => panic!("permission denied wasn't converted to error"),
+        }
+        match compatible_reply(permission_denied(), &[capability::PERMISSION_DENIED.to_owned()]) {
+            Reply::PermissionDenied { .. } => (),
+            _ => panic!("negotiated reply was converted"),
+        }
+        match compatible_reply(permission_denied(), &[]) {
+            Reply::OperationFailed => (),
+            _ => panic!("permission denied wasn't converted to operation failed"),
+        }
+
+        let error = Reply::Error { code: ErrorCode::StorageFailure, message: String::new() };
+        match compatible_reply(error, &[]) {
+            Reply::OperationFailed => (),
+            _ => panic!("error wasn't converted to operation failed"),
+        }
+    }
+
+    #[test]
+    fn missing_differs_from_null() {
+        let (mut handler, receiver) = handler(&[("null", json::Value::Null)]);
+
+        match reply(&mut handler, Operation::Get { key: "null".to_owned() }) {
+            Reply::Value { value, .. } => assert_eq!(value, json::Value::Null),
+            _ => panic!("getting null value failed"),
+        }
+        match reply(&mut handler, Operation::Get { key: "missing".to_owned() }) {
+            Reply::NotFound { key, .. } => assert_eq!(key, "missing"),
+            _ => panic!("missing key was found"),
+        }
+
+        for key in &["null", "missing"] {
+            let pattern = Pattern::Key(key.to_string());
+            match reply(&mut handler, Operation::Subscribe { pattern, notify_now: true }) {
+                Reply::OperationOk => (),
+                _ => panic!("subscribing failed"),
+            }
+        }
+
+        let expected = vec![
+            Change::Set { key: "null".to_owned(), value: json::Value::Null },
+            Change::Delete { key: "missing".to_owned() },
+        ];
+        assert_eq!(notified(handler, receiver), expected);
+    }
+
+    #[test]
+    fn missing_is_null_without_capability() {
+        use dscfg_proto::{capability, Expected};
+
+        let capabilities = CAPABILITIES
+            .iter()
+            .cloned()
+            .filter(|capability| *capability != capability::NOT_FOUND)
+            .collect::<Vec<_>>();
+        let (mut handler, receiver) = handler_with_capabilities(&[], &capabilities);
+
+        match reply(&mut handler, Operation::Get { key: "a".to_owned() }) {
+            Reply::Value { value, .. } => assert_eq!(value, json::Value::Null),
+            _ => panic!("missing key wasn't null"),
+        }
+        match compare_and_set(&mut handler, Expected::Value(json::Value::from(1)), 2) {
+            Reply::Conflict { value, .. } => assert_eq!(value, json::Value::Null),
+            _ => panic!("missing key wasn't null"),
+        }
+
+        let pattern = Pattern::Key("a".to_owned());
+        match reply(&mut handler, Operation::Subscribe { pattern, notify_now: true }) {
+            Reply::OperationOk => (),
+            _ => panic!("subscribing failed"),
+        }
+
+        assert_eq!(notified(handler, receiver), vec![Change::Set { key: "a".to_owned(), value: json::Value::Null }]);
+    }
+
+    fn compare_and_set(handler: &mut RequestHandler<Memory>, expected: dscfg_proto::Expected, value: i32) -> Reply {
+        let operation = Operation::CompareAndSet { key: "a".to_owned(), expected, value: json::Value::from(value) };
+        reply(handler, operation)
+    }
+
+    #[test]
+    fn compare_and_set_matching() {
+        use dscfg_proto::Expected;
+
+        let (mut handler, _receiver) = handler(&[("a", json::Value::from(1))]);
+
+        let revision = match compare_and_set(&mut handler, Expected::Value(json::Value::from(1)), 2) {
+            Reply::Updated { revision } => revision,
+            _ => panic!("compare-and-set with matching value failed"),
+        };
+        assert_eq!(handler.storage.0.get("a"), Some(&json::Value::from(2)));
+
+        match compare_and_set(&mut handler, Expected::Revision(revision), 3) {
+            Reply::Updated { revision: new } => assert_ne!(new, revision),
+            _ => panic!("compare-and-set with matching revision failed"),
+        }
+        assert_eq!(handler.storage.0.get("a"), Some(&json::Value::from(3)));
+    }
+
+    #[test]
+    fn compare_and_set_mismatch() {
+        use dscfg_proto::Expected;
+
+        let (mut handler, _receiver) = handler(&[("a", json::Value::from(1))]);
+
+        match compare_and_set(&mut handler, Expected::Value(json::Value::from(2)), 3) {
+            Reply::Conflict { key, value, .. } => {
+                assert_eq!(key, "a");
+                assert_eq!(value, json::Value::from(1));
+            },
+            _ => panic!("compare-and-set with different value didn't conflict"),
+        }
+
+        assert_eq!(handler.storage.0.get("a"), Some(&json::Value::from(1)));
+    }
+
+    #[test]
+    fn compare_and_set_absent() {
+        use dscfg_proto::Expected;
+
+        let (mut handler, _receiver) = handler(&[]);
+
+        match compare_and_set(&mut handler, Expected::Absent, 1) {
+            Reply::Updated { .. } => (),
+            _ => panic!("compare-and-set expecting absence failed"),
+        }
+        assert_eq!(handler.storage.0.get("a"), Some(&json::Value::from(1)));
+
+        match compare_and_set(&mut handler, Expected::Absent, 2) {
+            Reply::Conflict { value, .. } => assert_eq!(value, json::Value::from(1)),
+            _ => panic!("compare-and-set expecting absence of existing key didn't conflict"),
+        }
+    }
+
+    #[test]
+    fn transaction_set_then_delete() {
+        let (mut handler, receiver) = handler(&[]);
+        subscribe(&mut handler, "a");
+
+        let changes = vec![
+            Change::Set { key: "a".to_owned(), value: json::Value::from(1) },
+            Change::Delete { key: "a".to_owned() },
+        ];
+        transaction(&mut handler, changes.clone());
+
+        assert_eq!(handler.storage.0.get("a"), None);
+        assert_eq!(notified(handler, receiver), changes);
+    }
+
+    #[test]
+    fn transaction_delete_then_set() {
+        let (mut handler, receiver) = handler(&[]);
+        subscribe(&mut handler, "a");
+
+        let set = Change::Set { key: "a".to_owned(), value: json::Value::from(1) };
+        transaction(&mut handler, vec![Change::Delete { key: "a".to_owned() }, set.clone()]);
+
+        assert_eq!(handler.storage.0.get("a"), Some(&json::Value::from(1)));
+        assert_eq!(notified(handler, receiver), vec![set]);
+    }
+
+    #[test]
+    fn transaction_deletes_existing() {
+        let (mut handler, receiver) = handler(&[("a", json::Value::from(1))]);
+        subscribe(&mut handler, "a");
+
+        let changes = vec![
+            Change::Delete { key: "a".to_owned() },
+            Change::Delete { key: "a".to_owned() },
+        ];
+        transaction(&mut handler, changes);
+
+        assert_eq!(handler.storage.0.get("a"), None);
+        assert_eq!(notified(handler, receiver), vec![Change::Delete { key: "a".to_owned() }]);
+    }
+
+    #[test]
+    fn acl_denies_operations() {
+        use dscfg_proto::{Condition, Expected};
+
+        let values = [("public", json::Value::from(1)), ("secret", json::Value::from(2))];
+        let (mut handler, _receiver) = client(&restricted(), &values, CAPABILITIES, Arc::new(Anonymous));
+        let secret = || "secret".to_owned();
+        let set_secret = || Change::Set { key: secret(), value: json::Value::from(3) };
+
+        let operations = vec![
+            (Operation::Get { key: secret() }, Permission::Read),
+            (Operation::GetMany { keys: vec!["public".to_owned(), secret()] }, Permission::Read),
+            (Operation::Set { key: secret(), value: json::Value::from(3) }, Permission::Write),
+            (Operation::Delete { key: secret() }, Permission::Write),
+            (Operation::CompareAndSet { key: secret(), expected: Expected::Absent, value: json::Value::from(3) }, Permission::Read),
+            (Operation::Transaction { conditions: Vec::new(), changes: vec![set_secret()] }, Permission::Write),
+            (Operation::Transaction { conditions: vec![Condition { key: secret(), expected: Expected::Absent }], changes: Vec::new() }, Permission::Read),
+            (Operation::Subscribe { pattern: Pattern::Key(secret()), notify_now: true }, Permission::Subscribe),
+            (Operation::SubscribeMany { patterns: vec![Pattern::Key("public".to_owned()), Pattern::Key(secret())], notify_now: true }, Permission::Subscribe),
+        ];
+
+        for (operation, expected) in operations {
+            match reply(&mut handler, operation) {
+                Reply::PermissionDenied { key, permission } => {
+                    assert_eq!(key, "secret");
+                    assert_eq!(permission, expected);
+                },
+                _ => panic!("operation on secret key wasn't denied"),
+            }
+        }
+
+        match reply(&mut handler, Operation::List { prefix: String::new() }) {
+            Reply::Keys { keys } => assert_eq!(keys, ["public"]),
+            _ => panic!("listing failed"),
+        }
+        match reply(&mut handler, Operation::GetPrefix { prefix: String::new() }) {
+            Reply::Values { values } => assert_eq!(values.len(), 1),
+            _ => panic!("getting prefix failed"),
+        }
+
+        assert_eq!(handler.storage.0.get("secret"), Some(&json::Value::from(2)));
+        assert!(handler.shared.subscriptions.index.read().unwrap().identities.is_empty());
+    }
+
+    #[test]
+    fn acl_filters_broadcasts() {
+        let shared = restricted();
+        let (mut admin, _admin_receiver) = client(&shared, &[], CAPABILITIES, Arc::new("admin".to_owned()));
+        let (mut subscriber, receiver) = client(&shared, &[], CAPABILITIES, Arc::new(Anonymous));
+
+        let patterns = vec![Pattern::Prefix(String::new()), Pattern::Glob("*".to_owned())];
+        for pattern in patterns {
+            match reply(&mut subscriber, Operation::Subscribe { pattern, notify_now: false }) {
+                Reply::OperationOk => (),
+                _ => panic!("subscribing failed"),
+            }
+        }
+
+        let public = Change::Set { key: "public".to_owned(), value: json::Value::from(1) };
+        let secret = Change::Set { key: "secret".to_owned(), value: json::Value::from(2) };
+        let operations = vec![
+            Operation::Set { key: "secret".to_owned(), value: json::Value::from(2) },
+            Operation::Transaction { conditions: Vec::new(), changes: vec![public.clone(), secret] },
+            Operation::Delete { key: "secret".to_owned() },
+        ];
+        for operation in operations {
+            match reply(&mut admin, operation) {
+                Reply::OperationOk | Reply::Updated { .. } => (),
+                _ => panic!("admin's operation failed"),
+            }
+        }
+
+        // The subscriptions keep the sender of the subscriber, so all handles must be dropped.
+        std::mem::drop((shared, admin));
+        assert_eq!(notified(subscriber, receiver), vec![public]);
+    }
+
+    #[test]
+    fn transaction_without_effect() {
+        let (mut handler, receiver) = handler(&[]);
+        subscribe(&mut handler, "a");
+
+        let revision = match compare_and_set(&mut handler, dscfg_proto::Expected::Absent, 1) {
+            Reply::Updated { revision } => revision,
+            _ => panic!("compare-and-set failed"),
+        };
+        match reply(&mut handler, Operation::Delete { key: "a".to_owned() }) {
+            Reply::OperationOk => (),
+            _ => panic!("deleting failed"),
+        }
+
+        let changes = vec![Change::Delete { key: "a".to_owned() }, Change::Delete { key: "b".to_owned() }];
+        for changes in [Vec::new(), changes] {
+            match reply(&mut handler, Operation::Transaction { conditions: Vec::new(), changes }) {
+                Reply::Updated { revision: unchanged } => assert_eq!(unchanged, revision + 1),
+                _ => panic!("the transaction failed"),
+            }
+        }
+
+        let expected = vec![
+            Change::Set { key: "a".to_owned(), value: json::Value::from(1) },
+            Change::Delete { key: "a".to_owned() },
+        ];
+        assert_eq!(notified(handler, receiver), expected);
+    }
+
+    #[test]
+    fn subscribe_many_overlapping() {
+        let values = [("net.mtu", json::Value::from(1500)), ("net.ttl", json::Value::from(64)), ("other", json::Value::from(0))];
+        let (mut handler, receiver) = handler(&values);
+
+        let patterns = || vec![
+            Pattern::Key("net.mtu".to_owned()),
+            Pattern::Glob("net.*".to_owned()),
+            Pattern::Key("missing".to_owned()),
+        ];
+        match reply(&mut handler, Operation::SubscribeMany { patterns: patterns(), notify_now: true }) {
+            Reply::OperationOk => (),
+            _ => panic!("subscribing failed"),
+        }
+        match reply(&mut handler, Operation::SubscribeMany { patterns: patterns(), notify_now: false }) {
+            Reply::Ignored => (),
+            _ => panic!("subscribing again wasn't ignored"),
+        }
+
+        let changes = vec![
+            Change::Set { key: "net.mtu".to_owned(), value: json::Value::from(9000) },
+            Change::Set { key: "other".to_owned(), value: json::Value::from(1) },
+        ];
+        transaction(&mut handler, changes);
+
+        let expected = vec![
+            Change::Delete { key: "missing".to_owned() },
+            Change::Set { key: "net.mtu".to_owned(), value: json::Value::from(1500) },
+            Change::Set { key: "net.ttl".to_owned(), value: json::Value::from(64) },
+            Change::Set { key: "net.mtu".to_owned(), value: json::Value::from(9000) },
+        ];
+        assert_eq!(notified(handler, receiver), expected);
+    }
+
+    #[test]
+    fn get_many_mixed() {
+        use dscfg_proto::Entry;
+
+        let values = [("ab", json::Value::Null), ("b", json::Value::from(2))];
+        let (mut handler, _receiver) = handler(&values);
+        let revision = match compare_and_set(&mut handler, dscfg_proto::Expected::Absent, 1) {
+            Reply::Updated { revision } => revision,
+            _ => panic!("compare-and-set failed"),
+        };
+
+        let keys = vec!["missing".to_owned(), "a".to_owned(), "ab".to_owned(), "a".to_owned()];
+        let expected = vec![
+            Entry::Absent { key: "missing".to_owned() },
+            Entry::Present { key: "a".to_owned(), value: json::Value::from(1), revision },
+            Entry::Present { key: "ab".to_owned(), value: json::Value::Null, revision: 0 },
+            Entry::Present { key: "a".to_owned(), value: json::Value::from(1), revision },
+        ];
+        match reply(&mut handler, Operation::GetMany { keys }) {
+            Reply::Values { values } => assert_eq!(values, expected),
+            _ => panic!("getting many keys failed"),
+        }
+
+        let prefixes = vec![
+            ("a", vec![expected[1].clone(), expected[2].clone()]),
+            ("missing", Vec::new()),
+        ];
+        for (prefix, expected) in prefixes {
+            match reply(&mut handler, Operation::GetPrefix { prefix: prefix.to_owned() }) {
+                Reply::Values { values } => assert_eq!(values, expected),
+                _ => panic!("getting prefix failed"),
+            }
+        }
+    }
 }
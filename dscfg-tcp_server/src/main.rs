@@ -0,0 +1,146 @@
+#[macro_use]
+extern crate configure_me;
+extern crate dscfg_server;
+extern crate dscfg_cached_file_storage;
+extern crate dscfg_tls;
+extern crate void;
+extern crate tokio;
+#[macro_use]
+extern crate slog;
+extern crate slog_term;
+
+include_config!();
+
+use dscfg_server::ServerParams;
+use dscfg_server::acl::Acl;
+use dscfg_cached_file_storage::CachedFileStorage;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+/// Maximum number of TLS handshakes performed at the same time.
+const MAX_HANDSHAKES: usize = 64;
+
+trait Connection: AsyncRead + AsyncWrite + Send {
+    /// Returns the subject of the client certificate, if the client presented one.
+    fn certificate_subject(&self) -> Option<String>;
+}
+
+impl Connection for TcpStream {
+    fn certificate_subject(&self) -> Option<String> {
+        None
+    }
+}
+
+impl Connection for dscfg_tls::TlsStream<TcpStream> {
+    fn certificate_subject(&self) -> Option<String> {
+        self.peer_subject()
+    }
+}
+
+fn main() {
+    use tokio::prelude::{Future, Stream};
+    use tokio::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    let (cfg, _) = Config::including_optional_config_files(std::iter::empty::<std::path::PathBuf>()).unwrap_or_exit();
+
+    let authorizer = Acl::load(&cfg.policy).unwrap_or_else(|err| {
+        eprintln!("Failed to load policy {}: {}", cfg.policy.display(), err);
+        std::process::exit(1)
+    });
+
+    let addresses = cfg.bind
+        .split(',')
+        .map(|address| address.trim().parse::<SocketAddr>().unwrap_or_else(|err| {
+            eprintln!("Invalid address {}: {}", address, err);
+            std::process::exit(1)
+        }))
+        .collect::<Vec<_>>();
+
+    let tls_acceptor = match (cfg.tls_certificate, cfg.tls_private_key) {
+        (Some(certificate), Some(private_key)) => {
+            let acceptor = dscfg_tls::acceptor(&certificate, &private_key, cfg.tls_client_ca.as_ref().map(AsRef::as_ref)).unwrap_or_else(|err| {
+                eprintln!("Failed to set up TLS: {}", err);
+                std::process::exit(1)
+            });
+            Some(acceptor)
+        },
+        (None, None) if cfg.tls_client_ca.is_none() => None,
+        _ => {
+            eprintln!("Both tls_certificate and tls_private_key must be specified in order to use TLS");
+            std::process::exit(1)
+        },
+    };
+
+    let storage = CachedFileStorage::load_or_create(cfg.file).unwrap();
+    let logger = slog::Logger::root(slog::Fuse(Mutex::new(slog_term::term_full())), o!());
+
+    let mut incoming_clients: Box<dyn Stream<Item=TcpStream, Error=std::io::Error> + Send> = Box::new(tokio::prelude::stream::empty());
+    for address in addresses {
+        let listener = TcpListener::bind(&address).unwrap_or_else(|err| {
+            eprintln!("Failed to bind {}: {}", address, err);
+            std::process::exit(1)
+        });
+        info!(logger, "Listening"; "address" => %address);
+        incoming_clients = Box::new(incoming_clients.select(listener.incoming()));
+    }
+
+    // The messages are small and the clients wait for replies, so delaying them doesn't help.
+    // Failing to turn it off only affects latency, so it's not a reason to reject the client.
+    let incoming_clients = incoming_clients.map(|stream| {
+        let _ = stream.set_nodelay(true);
+        stream
+    });
+
+    let incoming_clients: Box<dyn Stream<Item=Box<dyn Connection>, Error=std::io::Error> + Send> = match tls_acceptor {
+        Some(acceptor) => {
+            let logger = logger.clone();
+            // A failed handshake only affects a single client, so it must not stop the server.
+            let incoming_clients = incoming_clients
+                .map(move |stream| {
+                    let address = stream.peer_addr();
+                    dscfg_tls::accept(&acceptor, stream).then(|result| Ok::<_, std::io::Error>((address, result)))
+                })
+                .buffer_unordered(MAX_HANDSHAKES)
+                .filter_map(move |(address, result)| {
+                    let address = address.map(|address| address.to_string()).unwrap_or_default();
+                    match result {
+                        Ok(stream) => {
+                            debug!(logger, "TLS handshake finished"; "address" => address);
+                            Some(Box::new(stream) as Box<dyn Connection>)
+                        },
+                        Err(err) => {
+                            info!(logger, "rejecting client"; "address" => address, "cause" => %err);
+                            None
+                        },
+                    }
+                });
+            Box::new(incoming_clients)
+        },
+        None => Box::new(incoming_clients.map(|stream| Box::new(stream) as Box<dyn Connection>)),
+    };
+
+    // Clients that didn't present a certificate can't be told apart.
+    #[allow(clippy::borrowed_box)]
+    let authenticator = |connection: &Box<dyn Connection>| -> Result<String, void::Void> {
+        Ok(connection.certificate_subject().unwrap_or_else(|| dscfg_server::Anonymous.to_string()))
+    };
+
+    let server_params = ServerParams {
+        storage: Arc::new(Mutex::new(storage)),
+        executor: tokio::executor::DefaultExecutor::current(),
+        incoming_clients,
+        authenticator,
+        authorizer,
+        logger,
+    };
+
+    info!(server_params.logger, "Starting the server");
+
+    let server = dscfg_server::serve(server_params).map_err(|err| {
+        println!("Server failed: {:?}", err);
+    });
+
+    tokio::run(server);
+}
@@ -0,0 +1,220 @@
+//! TLS transport for dscfg
+//!
+//! This crate wraps OpenSSL streams so that they can be used with `tokio` 0.1 based clients and
+//! servers. The server can optionally require clients to present certificates signed by a
+//! specified certificate authority (mutual TLS), in which case the subject of the client
+//! certificate can be used to identify the client.
+
+pub use openssl;
+pub use openssl::ssl::{SslAcceptor, SslConnector};
+
+use futures::{Async, Future, Poll};
+use openssl::error::ErrorStack;
+use openssl::ssl::{self, HandshakeError, MidHandshakeSslStream, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::{X509Name, X509NameRef};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Creates acceptor for the server using the certificate chain and private key in PEM format.
+///
+/// If `client_ca` is specified, the clients are required to present a certificate signed by
+/// one of the certificate authorities in the file. Otherwise client certificates aren't
+/// requested.
+pub fn acceptor(certificate_chain: &Path, private_key: &Path, client_ca: Option<&Path>) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder.set_certificate_chain_file(certificate_chain)?;
+    builder.set_private_key_file(private_key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+
+    if let Some(client_ca) = client_ca {
+        builder.set_ca_file(client_ca)?;
+        builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca)?);
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+
+    Ok(builder.build())
+}
+
+/// Creates connector for the client.
+///
+/// The server certificate is verified using `ca` if specified, otherwise using the default
+/// certificate authorities of the system. `identity` is a pair of paths to the client certificate
+/// chain and private key in PEM format, which is required by servers using mutual TLS.
+pub fn connector(ca: Option<&Path>, identity: Option<(&Path, &Path)>) -> Result<SslConnector, ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+
+    if let Some(ca) = ca {
+        builder.set_ca_file(ca)?;
+    }
+
+    if let Some((certificate_chain, private_key)) = identity {
+        builder.set_certificate_chain_file(certificate_chain)?;
+        builder.set_private_key_file(private_key, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+    }
+
+    Ok(builder.build())
+}
+
+/// Performs server side of the handshake on `stream`.
+pub fn accept<S: AsyncRead + AsyncWrite>(acceptor: &SslAcceptor, stream: S) -> Handshake<S> {
+    Handshake(Some(acceptor.accept(stream)))
+}
+
+/// Performs client side of the handshake on `stream`.
+///
+/// The server certificate must be valid for `domain`, which may also be an IP address.
+pub fn connect<S: AsyncRead + AsyncWrite>(connector: &SslConnector, domain: &str, stream: S) -> Handshake<S> {
+    Handshake(Some(connector.connect(domain, stream)))
+}
+
+fn ssl_error(error: ssl::Error) -> io::Error {
+    error
+        .into_io_error()
+        .unwrap_or_else(io::Error::other)
+}
+
+/// Future resolving to `TlsStream` once the handshake finishes.
+pub struct Handshake<S>(Option<Result<SslStream<S>, HandshakeError<S>>>);
+
+impl<S: AsyncRead + AsyncWrite> Future for Handshake<S> {
+    type Item = TlsStream<S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // The first attempt is made outside of the task, so interrupted handshake is always
+        // resumed here in order to register interest in the stream.
+        let result = match self.0.take().expect("polled after completion") {
+            Err(HandshakeError::WouldBlock(stream)) => MidHandshakeSslStream::handshake(stream),
+            result => result,
+        };
+
+        match result {
+            Ok(stream) => Ok(Async::Ready(TlsStream(stream))),
+            Err(HandshakeError::WouldBlock(stream)) => {
+                self.0 = Some(Err(HandshakeError::WouldBlock(stream)));
+                Ok(Async::NotReady)
+            },
+            Err(HandshakeError::Failure(stream)) => Err(ssl_error(stream.into_error())),
+            Err(HandshakeError::SetupFailure(error)) => Err(io::Error::other(error)),
+        }
+    }
+}
+
+/// Encrypted stream
+pub struct TlsStream<S>(SslStream<S>);
+
+impl<S> TlsStream<S> {
+    /// Returns the subject of the certificate presented by the peer.
+    ///
+    /// The subject is formatted as comma-separated list of `NAME=value` entries in the order in
+    /// which they appear in the certificate, e.g. `C=SK,O=Example,CN=client`. Special characters
+    /// in the values are escaped as described in RFC 4514, so a value can't pretend to be several
+    /// entries. Returns `None` if the peer didn't present any certificate, which is always the case
+    /// on the server side if the acceptor wasn't configured to require client certificates.
+    pub fn peer_subject(&self) -> Option<String> {
+        let certificate = self.0.ssl().peer_certificate()?;
+        Some(format_name(certificate.subject_name()))
+    }
+
+    /// Returns reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        self.0.get_ref()
+    }
+}
+
+/// Formats the entries of the `name` as comma-separated list of `NAME=value`.
+fn format_name(name: &X509NameRef) -> String {
+    let entries = name
+        .entries()
+        .map(|entry| {
+            let name = entry.object().nid().short_name().unwrap_or("UNDEF");
+            let value = entry.data().to_string().unwrap_or_default();
+            format!("{}={}", name, escape_value(&value))
+        })
+        .collect::<Vec<_>>();
+
+    entries.join(",")
+}
+
+/// Escapes the attribute value according to section 2.4 of RFC 4514.
+fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => escaped.push('\\'),
+            '#' if i == 0 => escaped.push('\\'),
+            ' ' if i == 0 || i == last => escaped.push('\\'),
+            '\0' => {
+                escaped.push_str("\\00");
+                continue;
+            },
+            _ => (),
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.shutdown() {
+            Ok(_) => (),
+            // The peer already closed the connection.
+            Err(ref error) if error.code() == ssl::ErrorCode::ZERO_RETURN => (),
+            Err(error) => match ssl_error(error) {
+                ref error if error.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                error => return Err(error),
+            },
+        }
+
+        self.0.get_mut().shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_value, format_name};
+    use openssl::x509::X509Name;
+
+    #[test]
+    fn escapes_subject() {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("O", "Example, Inc.").unwrap();
+        name.append_entry_by_text("CN", "client+CN=admin").unwrap();
+        let name = name.build();
+
+        assert_eq!(format_name(&name), r"O=Example\, Inc.,CN=client\+CN=admin");
+    }
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(escape_value(r#"a"b;c<d>e\f"#), r#"a\"b\;c\<d\>e\\f"#);
+        assert_eq!(escape_value("#a#"), r"\#a#");
+        assert_eq!(escape_value(" a b "), r"\ a b\ ");
+        assert_eq!(escape_value(" "), r"\ ");
+        assert_eq!(escape_value("a\0b"), r"a\00b");
+    }
+}
@@ -0,0 +1,245 @@
+//! Identification of clients using the credentials of the connected process.
+
+use dscfg_server::Identity;
+use libc::{gid_t, pid_t, uid_t};
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use tokio::net::unix::UnixStream;
+use users::{group_name, user_groups, user_name};
+
+/// Group with its name, if the name could be looked up.
+struct Group {
+    id: gid_t,
+    name: Option<String>,
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if let Some(name) = &self.name {
+            write!(f, "({})", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Credentials of the process which connected to the socket.
+///
+/// The credentials are captured by the kernel when the process connects, so they can't be forged.
+/// Apart from the user and the primary group, the supplementary groups of the user are looked up
+/// in the user database.
+///
+/// The identity has these principals:
+///
+/// * `uid:N` - the process runs as the user with ID `N`
+/// * `user:NAME` - the process runs as the user named `NAME`
+/// * `gid:N` - the process runs with the primary group `N` or the user is a member of the group
+/// * `group:NAME` - same as above, but the group is specified by name
+pub struct PeerCredentials {
+    uid: uid_t,
+    gid: gid_t,
+    pid: Option<pid_t>,
+    // Looked up when a principal needs it for the first time.
+    names: Mutex<Option<Names>>,
+}
+
+/// Information about the user from the user database.
+struct Names {
+    user: Option<String>,
+    // The primary group is always first.
+    groups: Vec<Group>,
+}
+
+impl Names {
+    fn look_up(uid: uid_t, gid: gid_t) -> io::Result<Self> {
+        let user = user_name(uid)?;
+        let mut group_ids = match &user {
+            Some(user) => user_groups(user, gid)?,
+            None => Vec::new(),
+        };
+        group_ids.retain(|group| *group != gid);
+        group_ids.insert(0, gid);
+
+        let groups = group_ids
+            .into_iter()
+            .map(|id| Ok(Group { id, name: group_name(id)?, }))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Names {
+            user,
+            groups,
+        })
+    }
+}
+
+impl PeerCredentials {
+    /// Retrieves the credentials of the process connected to the other end of `stream`.
+    ///
+    /// The user database isn't accessed here, because it may block if it's stored remotely. The
+    /// names and the supplementary groups are looked up by the first `has_principal()` call which
+    /// needs them, so clients matched by `uid:` principals or the primary group never wait for it.
+    pub fn of(stream: &UnixStream) -> io::Result<Self> {
+        let (uid, gid, pid) = peer_credentials(stream)?;
+
+        Ok(PeerCredentials {
+            uid,
+            gid,
+            pid,
+            names: Mutex::new(None),
+        })
+    }
+
+    /// Calls `f` with the names looked up in the user database.
+    ///
+    /// Returns `false` if the lookup fails, it's attempted again next time.
+    fn with_names<F: FnOnce(&Names) -> bool>(&self, f: F) -> bool {
+        // A poisoned lock means that a previous lookup panicked, so the names are missing.
+        let mut names = self.names.lock().unwrap_or_else(|err| err.into_inner());
+        if names.is_none() {
+            *names = Names::look_up(self.uid, self.gid).ok();
+        }
+
+        names.as_ref().is_some_and(f)
+    }
+}
+
+impl fmt::Display for PeerCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "uid={}", self.uid)?;
+
+        // The names are shown only if they were already looked up, formatting must not block.
+        match &*self.names.lock().unwrap_or_else(|err| err.into_inner()) {
+            Some(names) => {
+                if let Some(user) = &names.user {
+                    write!(f, "({})", user)?;
+                }
+                write!(f, " gid={} groups=", names.groups[0])?;
+                for (i, group) in names.groups.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", group)?;
+                }
+            },
+            None => write!(f, " gid={}", self.gid)?,
+        }
+
+        if let Some(pid) = self.pid {
+            write!(f, " pid={}", pid)?;
+        }
+        Ok(())
+    }
+}
+
+impl Identity for PeerCredentials {
+    fn has_principal(&self, principal: &str) -> bool {
+        let mut parts = principal.splitn(2, ':');
+        let (kind, value) = match (parts.next(), parts.next()) {
+            (Some(kind), Some(value)) => (kind, value),
+            _ => return false,
+        };
+
+        match kind {
+            "uid" => value.parse() == Ok(self.uid),
+            "user" => self.with_names(|names| names.user.as_deref() == Some(value)),
+            "gid" => match value.parse::<gid_t>() {
+                Ok(gid) => gid == self.gid || self.with_names(|names| names.groups.iter().any(|group| group.id == gid)),
+                Err(_) => false,
+            },
+            "group" => self.with_names(|names| names.groups.iter().any(|group| group.name.as_deref() == Some(value))),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(stream: &UnixStream) -> io::Result<(uid_t, gid_t, Option<pid_t>)> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0, };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((credentials.uid, credentials.gid, Some(credentials.pid)))
+}
+
+// Other systems don't provide the process ID.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_credentials(stream: &UnixStream) -> io::Result<(uid_t, gid_t, Option<pid_t>)> {
+    let credentials = stream.peer_cred()?;
+    Ok((credentials.uid, credentials.gid, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Group, Names, PeerCredentials};
+    use dscfg_server::Identity;
+    use std::sync::Mutex;
+
+    fn credentials() -> PeerCredentials {
+        let names = Names {
+            user: Some("alice".to_owned()),
+            groups: vec![
+                Group { id: 1000, name: Some("alice".to_owned()) },
+                Group { id: 27, name: Some("sudo".to_owned()) },
+                Group { id: 1234, name: None },
+            ],
+        };
+
+        PeerCredentials {
+            uid: 1000,
+            gid: 1000,
+            pid: Some(42),
+            names: Mutex::new(Some(names)),
+        }
+    }
+
+    #[test]
+    fn has_principal() {
+        let credentials = credentials();
+
+        assert!(credentials.has_principal("uid:1000"));
+        assert!(credentials.has_principal("user:alice"));
+        assert!(credentials.has_principal("gid:1000"));
+        assert!(credentials.has_principal("gid:27"));
+        assert!(credentials.has_principal("gid:1234"));
+        assert!(credentials.has_principal("group:alice"));
+        assert!(credentials.has_principal("group:sudo"));
+
+        assert!(!credentials.has_principal("uid:0"));
+        assert!(!credentials.has_principal("uid:alice"));
+        assert!(!credentials.has_principal("user:bob"));
+        assert!(!credentials.has_principal("gid:0"));
+        assert!(!credentials.has_principal("group:wheel"));
+        assert!(!credentials.has_principal("pid:42"));
+        assert!(!credentials.has_principal("alice"));
+        assert!(!credentials.has_principal(""));
+    }
+
+    #[test]
+    fn names_looked_up_lazily() {
+        // IDs that are very unlikely to exist in the user database.
+        let credentials = PeerCredentials {
+            uid: 3_999_999_999,
+            gid: 3_999_999_998,
+            pid: None,
+            names: Mutex::new(None),
+        };
+
+        assert!(credentials.has_principal("uid:3999999999"));
+        assert!(credentials.has_principal("gid:3999999998"));
+        assert!(credentials.names.lock().unwrap().is_none());
+        assert_eq!(credentials.to_string(), "uid=3999999999 gid=3999999998");
+
+        assert!(!credentials.has_principal("user:root"));
+        assert!(!credentials.has_principal("gid:0"));
+        assert!(credentials.names.lock().unwrap().is_some());
+        assert_eq!(credentials.to_string(), "uid=3999999999 gid=3999999998 groups=3999999998");
+    }
+}
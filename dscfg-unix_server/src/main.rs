@@ -4,6 +4,7 @@ extern crate dscfg_server;
 extern crate dscfg_cached_file_storage;
 extern crate serde_json;
 extern crate void;
+extern crate libc;
 extern crate tokio;
 #[macro_use]
 extern crate slog;
@@ -11,8 +12,14 @@ extern crate slog_term;
 
 include_config!();
 
-use dscfg_server::ServerParams;
+mod credentials;
+mod socket;
+mod users;
+
+use dscfg_server::{Authorizer, ServerParams};
+use dscfg_server::acl::Acl;
 use dscfg_cached_file_storage::CachedFileStorage;
+use credentials::PeerCredentials;
 
 fn main() {
     use tokio::prelude::Future;
@@ -20,14 +27,39 @@ fn main() {
 
     let (cfg, _) = Config::including_optional_config_files(std::iter::empty::<std::path::PathBuf>()).unwrap_or_exit();
 
-    let storage = CachedFileStorage::load_or_create(cfg.file).unwrap();
-    let listener = tokio::net::unix::UnixListener::bind(cfg.socket).unwrap();
+    let authorizer: Box<dyn Authorizer> = match cfg.policy {
+        Some(policy) => Box::new(Acl::load(&policy).unwrap_or_else(|err| {
+            eprintln!("Failed to load policy {}: {}", policy.display(), err);
+            std::process::exit(1)
+        })),
+        None => Box::new(dscfg_server::AllowAll),
+    };
+
+    let permissions = socket::Permissions {
+        mode: cfg.socket_mode.map(|mode| parse_mode(&mode)),
+        owner: cfg.socket_owner.map(|owner| resolve("user", &owner, users::user_id)),
+        group: cfg.socket_group.map(|group| resolve("group", &group, users::group_id)),
+    };
+
+    let socket_path = cfg.socket;
+    let storage = Arc::new(Mutex::new(CachedFileStorage::load_or_create(cfg.file).unwrap()));
+    let listener = socket::bind(&socket_path, &permissions).unwrap_or_else(|err| {
+        eprintln!("Failed to bind {}: {}", socket_path.display(), err);
+        std::process::exit(1)
+    });
     let logger = slog::Logger::root(slog::Fuse(Mutex::new(slog_term::term_full())), o!());
 
+    if let Err(err) = socket::remove_on_termination(socket_path.clone(), Arc::clone(&storage), logger.clone()) {
+        eprintln!("Failed to set up signal handling: {}", err);
+        std::process::exit(1)
+    }
+
     let server_params = ServerParams {
-        storage: Arc::new(Mutex::new(storage)),
+        storage,
         executor: tokio::executor::DefaultExecutor::current(),
         incoming_clients: listener.incoming(),
+        authenticator: PeerCredentials::of,
+        authorizer,
         logger: logger,
     };
 
@@ -38,4 +70,31 @@ fn main() {
     });
 
     tokio::run(server);
+
+    let _ = socket::remove(&socket_path);
+}
+
+fn parse_mode(mode: &str) -> u32 {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => mode,
+        _ => {
+            eprintln!("Invalid socket mode {}, expected octal number such as 660", mode);
+            std::process::exit(1)
+        },
+    }
+}
+
+/// Looks up ID of user or group specified by name or number.
+fn resolve<T>(kind: &str, name: &str, lookup: fn(&str) -> std::io::Result<Option<T>>) -> T {
+    match lookup(name) {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            eprintln!("Unknown {} {}", kind, name);
+            std::process::exit(1)
+        },
+        Err(err) => {
+            eprintln!("Failed to look up {} {}: {}", kind, name, err);
+            std::process::exit(1)
+        },
+    }
 }
@@ -0,0 +1,280 @@
+//! Creation and removal of the socket the server listens on.
+
+use libc::{gid_t, uid_t};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::net::unix::UnixListener;
+
+/// Access rights of the socket file, `None` means default.
+#[derive(Debug, Default)]
+pub struct Permissions {
+    /// Permission bits, e.g. `0o660`.
+    pub mode: Option<u32>,
+    /// The user owning the socket.
+    pub owner: Option<uid_t>,
+    /// The group owning the socket.
+    pub group: Option<gid_t>,
+}
+
+/// Error returned when the socket can't be created.
+#[derive(Debug)]
+pub enum BindError {
+    /// Locking the socket failed.
+    Lock(io::Error),
+    /// Another server is listening on the socket.
+    InUse,
+    /// The path exists but it's not a socket, so it isn't removed.
+    NotSocket,
+    /// The socket was left by a server that isn't running anymore and removing it failed.
+    RemoveStale(io::Error),
+    /// Creating the socket failed.
+    Bind(io::Error),
+    /// Changing the permissions or the owner of the socket failed.
+    SetPermissions(io::Error),
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindError::Lock(err) => write!(f, "failed to lock the socket: {}", err),
+            BindError::InUse => write!(f, "another server is listening on the socket"),
+            BindError::NotSocket => write!(f, "the path already exists and isn't a socket"),
+            BindError::RemoveStale(err) => write!(f, "failed to remove stale socket: {}", err),
+            BindError::Bind(err) => write!(f, "{}", err),
+            BindError::SetPermissions(err) => write!(f, "failed to set permissions of the socket: {}", err),
+        }
+    }
+}
+
+/// Restores the original umask when dropped.
+///
+/// The umask is shared by all threads, so it may be only changed before spawning other threads.
+struct Umask(libc::mode_t);
+
+impl Umask {
+    fn set(mask: libc::mode_t) -> Self {
+        Umask(unsafe { libc::umask(mask) })
+    }
+}
+
+impl Drop for Umask {
+    fn drop(&mut self) {
+        unsafe {
+            libc::umask(self.0);
+        }
+    }
+}
+
+/// Creates the socket at `path` with `permissions`.
+///
+/// If the path contains a socket left by a server which crashed, the socket is removed. The
+/// socket is considered stale if connecting to it is refused, so a running server is never
+/// replaced. The file `path` with `.lock` appended is locked while creating the socket, so servers
+/// started at the same time can't remove the socket created by the other one.
+pub fn bind(path: &Path, permissions: &Permissions) -> Result<UnixListener, BindError> {
+    let _lock = lock(path).map_err(BindError::Lock)?;
+    remove_stale(path)?;
+
+    let listener = {
+        // Nobody else may connect before the requested permissions are set.
+        let _umask = permissions.mode.map(|_| Umask::set(0o177));
+        UnixListener::bind(path).map_err(BindError::Bind)?
+    };
+
+    if let Err(err) = set_permissions(path, permissions) {
+        let _ = fs::remove_file(path);
+        return Err(BindError::SetPermissions(err));
+    }
+
+    Ok(listener)
+}
+
+/// Exclusively locks the lock file of the socket at `path`, waiting for other servers to release it.
+///
+/// The lock is released when the returned file is closed.
+fn lock(path: &Path) -> io::Result<fs::File> {
+    let file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(lock_path(path))?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(file)
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    lock_path.into()
+}
+
+/// Removes the socket at `path` created by `bind` together with its lock file.
+pub fn remove(path: &Path) -> io::Result<()> {
+    fs::remove_file(path)?;
+    match fs::remove_file(lock_path(path)) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn remove_stale(path: &Path) -> Result<(), BindError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(BindError::RemoveStale(err)),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(BindError::NotSocket);
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(BindError::InUse),
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path).map_err(BindError::RemoveStale),
+        Err(err) => Err(BindError::RemoveStale(err)),
+    }
+}
+
+fn set_permissions(path: &Path, permissions: &Permissions) -> io::Result<()> {
+    if permissions.owner.is_some() || permissions.group.is_some() {
+        std::os::unix::fs::chown(path, permissions.owner, permissions.group)?;
+    }
+
+    if let Some(mode) = permissions.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(())
+}
+
+/// Removes the socket at `path` with its lock file and exits when the process receives `SIGINT` or `SIGTERM`.
+///
+/// The signals are handled by a dedicated thread, so this must be called before spawning other
+/// threads, otherwise the signals could be delivered to them. The `storage` is locked before
+/// exiting, so that the server doesn't exit in the middle of storing the configuration.
+pub fn remove_on_termination<T: Send + 'static>(path: PathBuf, storage: Arc<Mutex<T>>, logger: slog::Logger) -> io::Result<()> {
+    let signals = unsafe {
+        let mut signals = std::mem::zeroed::<libc::sigset_t>();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        signals
+    };
+
+    // Threads spawned later inherit the mask, so only the thread below receives the signals.
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result));
+    }
+
+    std::thread::Builder::new()
+        .name("signals".to_owned())
+        .spawn(move || {
+            let mut signal = 0;
+            let result = unsafe { libc::sigwait(&signals, &mut signal) };
+            if result != 0 {
+                error!(logger, "waiting for signals failed"; "error" => %io::Error::from_raw_os_error(result));
+                return;
+            }
+
+            info!(logger, "Terminating"; "signal" => signal);
+            // A poisoned lock means that storing failed anyway.
+            let _storage = storage.lock();
+            if let Err(err) = remove(&path) {
+                error!(logger, "failed to remove the socket"; "error" => %err);
+            }
+            std::process::exit(0)
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind, remove, remove_stale, BindError, Permissions};
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+
+    /// Creates an empty directory unique for the test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dscfg-socket-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn remove_stale_socket() {
+        let dir = temp_dir("stale");
+        let path = dir.join("socket");
+
+        remove_stale(&path).unwrap();
+
+        // Dropping the listener doesn't remove the socket, just like crashing.
+        std::mem::drop(UnixListener::bind(&path).unwrap());
+        remove_stale(&path).unwrap();
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keep_socket_in_use() {
+        let dir = temp_dir("in_use");
+        let path = dir.join("socket");
+
+        let _listener = UnixListener::bind(&path).unwrap();
+        match remove_stale(&path) {
+            Err(BindError::InUse) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keep_other_files() {
+        let dir = temp_dir("not_socket");
+        let path = dir.join("socket");
+
+        std::fs::write(&path, "").unwrap();
+        match remove_stale(&path) {
+            Err(BindError::NotSocket) => (),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bind_with_permissions() {
+        let dir = temp_dir("permissions");
+        let path = dir.join("socket");
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let permissions = Permissions {
+            mode: Some(0o640),
+            owner: Some(uid),
+            group: Some(gid),
+        };
+
+        let listener = bind(&path, &permissions).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o640);
+        assert_eq!(metadata.uid(), uid);
+        assert_eq!(metadata.gid(), gid);
+
+        std::mem::drop(listener);
+        remove(&path).unwrap();
+        assert!(!path.exists());
+        assert!(!dir.join("socket.lock").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! Lookups in the user database.
+
+use libc::{gid_t, uid_t};
+use std::ffi::{CStr, CString};
+use std::io;
+
+/// Calls `lookup` with growing buffer until it's large enough.
+///
+/// `lookup` returns an error number as the `*_r` functions do.
+fn with_buffer<T, F: FnMut(&mut [libc::c_char]) -> Result<T, libc::c_int>>(mut lookup: F) -> io::Result<T> {
+    let mut buf = vec![0; 1024];
+    loop {
+        match lookup(&mut buf) {
+            Ok(value) => return Ok(value),
+            Err(libc::ERANGE) => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            },
+            Err(errno) => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+/// Returns the name of the user with `uid`, if there is such user.
+pub fn user_name(uid: uid_t) -> io::Result<Option<String>> {
+    with_buffer(|buf| unsafe {
+        let mut passwd = std::mem::zeroed::<libc::passwd>();
+        let mut result = std::ptr::null_mut();
+        match libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) {
+            0 if result.is_null() => Ok(None),
+            0 => Ok(Some(CStr::from_ptr(passwd.pw_name).to_string_lossy().into_owned())),
+            errno => Err(errno),
+        }
+    })
+}
+
+/// Returns the name of the group with `gid`, if there is such group.
+pub fn group_name(gid: gid_t) -> io::Result<Option<String>> {
+    with_buffer(|buf| unsafe {
+        let mut group = std::mem::zeroed::<libc::group>();
+        let mut result = std::ptr::null_mut();
+        match libc::getgrgid_r(gid, &mut group, buf.as_mut_ptr(), buf.len(), &mut result) {
+            0 if result.is_null() => Ok(None),
+            0 => Ok(Some(CStr::from_ptr(group.gr_name).to_string_lossy().into_owned())),
+            errno => Err(errno),
+        }
+    })
+}
+
+/// Returns the ID of the user specified by name or numeric ID.
+pub fn user_id(user: &str) -> io::Result<Option<uid_t>> {
+    if let Ok(uid) = user.parse() {
+        return Ok(Some(uid));
+    }
+
+    let name = c_string(user)?;
+    with_buffer(|buf| unsafe {
+        let mut passwd = std::mem::zeroed::<libc::passwd>();
+        let mut result = std::ptr::null_mut();
+        match libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) {
+            0 if result.is_null() => Ok(None),
+            0 => Ok(Some(passwd.pw_uid)),
+            errno => Err(errno),
+        }
+    })
+}
+
+/// Returns the ID of the group specified by name or numeric ID.
+pub fn group_id(group: &str) -> io::Result<Option<gid_t>> {
+    if let Ok(gid) = group.parse() {
+        return Ok(Some(gid));
+    }
+
+    let name = c_string(group)?;
+    with_buffer(|buf| unsafe {
+        let mut group = std::mem::zeroed::<libc::group>();
+        let mut result = std::ptr::null_mut();
+        match libc::getgrnam_r(name.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut result) {
+            0 if result.is_null() => Ok(None),
+            0 => Ok(Some(group.gr_gid)),
+            errno => Err(errno),
+        }
+    })
+}
+
+fn c_string(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Returns the IDs of the groups the `user` is member of, including `gid`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn user_groups(user: &str, gid: gid_t) -> io::Result<Vec<gid_t>> {
+    let user = c_string(user)?;
+    let mut groups = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let result = unsafe { libc::getgrouplist(user.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if result >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // The count was updated to the number of the groups the user is member of.
+        let len = std::cmp::max(count as usize, groups.len() * 2);
+        groups.resize(len, 0);
+    }
+}
+
+// The signature of `getgrouplist` differs between systems, so only the primary group is used.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn user_groups(_user: &str, gid: gid_t) -> io::Result<Vec<gid_t>> {
+    Ok(vec![gid])
+}
@@ -2,22 +2,117 @@ extern crate dscfg_client;
 extern crate tokio;
 extern crate serde_json;
 
+use tokio::prelude::Future;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::net::SocketAddr;
+use dscfg_client::tls::SslConnector;
+
+/// Server to connect to.
+enum Server {
+    Unix(OsString),
+    Tcp(SocketAddr),
+    Tls { address: SocketAddr, domain: String, connector: SslConnector },
+}
+
+/// Stream connected to the server.
+trait Connection: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send {}
+
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send> Connection for T {}
+
+fn connect(server: Server) -> Box<dyn Future<Item=Box<dyn Connection>, Error=std::io::Error> + Send> {
+    match server {
+        Server::Unix(path) => Box::new(tokio::net::unix::UnixStream::connect(path).map(|stream| Box::new(stream) as Box<dyn Connection>)),
+        Server::Tcp(address) => Box::new(tokio::net::TcpStream::connect(&address).map(|stream| Box::new(stream) as Box<dyn Connection>)),
+        Server::Tls { address, domain, connector } => {
+            let connection = tokio::net::TcpStream::connect(&address)
+                .and_then(move |stream| dscfg_client::tls::connect(&connector, &domain, stream))
+                .map(|stream| Box::new(stream) as Box<dyn Connection>);
+            Box::new(connection)
+        },
+    }
+}
+
 fn print_help<P: AsRef<::std::path::Path>>(program_path: P) -> ! {
-    println!("Usage: {} SOCKET (set KEY VALUE|listen KEY [KEYS...]|get KEY)", program_path.as_ref().display());
+    println!("Usage: {} (SOCKET|--tcp ADDRESS|--tls ADDRESS [--ca FILE] [--cert FILE --key FILE]) (set KEY VALUE|listen KEY [KEYS...]|get KEY [KEYS...]|delete KEY|list [PREFIX]|dump [PREFIX])", program_path.as_ref().display());
     println!();
     println!("Arguments:");
     println!("\tSOCKET         Unix socket to connect to.");
+    println!("\tADDRESS        TCP address to connect to in the form HOST:PORT.");
+    println!("\t               When using TLS, the server certificate must be valid for HOST.");
+    println!("\t--ca FILE      PEM file with certificate authorities used to verify the server.");
+    println!("\t               The system defaults are used if not specified.");
+    println!("\t--cert FILE    PEM file with client certificate chain for servers requiring it.");
+    println!("\t--key FILE     PEM file with private key of the client certificate.");
     println!("\tKEY            UTF-8 string identifying a setting.");
+    println!("\t               When listening, `*` and `?` may be used as wildcards.");
     println!("\tVALUE          JSON-encoded value. (Doesn't have to be an object.)");
+    println!("\tPREFIX         Only keys starting with this UTF-8 string are listed or dumped.");
+    println!();
+    println!("When getting several keys or dumping, each line contains the key and its value.");
+    println!();
+    println!("Exit status:");
+    println!("\t0              Success.");
+    println!("\t1              Invalid arguments or the operation failed.");
+    println!("\t2              Some of the keys to get or the key to delete don't exist.");
     std::process::exit(1)
 }
 
 fn main() {
-    use tokio::prelude::{Future, Stream};
+    use tokio::prelude::Stream;
+    use std::net::ToSocketAddrs;
 
-    let mut args = std::env::args_os();
+    let mut args = std::env::args_os().peekable();
     let program_path = args.next().expect("Not even zeroth argument given");
-    let socket_path = args.next().unwrap_or_else(|| print_help(&program_path));
+    let server = args.next().unwrap_or_else(|| print_help(&program_path));
+    let server = if server == *"--tcp" || server == *"--tls" {
+        let address = args
+            .next()
+            .unwrap_or_else(|| print_help(&program_path))
+            .into_string()
+            .unwrap_or_else(|_| { println!("Address isn't a UTF-8 string"); print_help(&program_path); });
+
+        let socket_address = address
+            .to_socket_addrs()
+            .unwrap_or_else(|err| { eprintln!("Invalid address {}: {}", address, err); std::process::exit(1) })
+            .next()
+            .unwrap_or_else(|| { eprintln!("The address {} didn't resolve to anything", address); std::process::exit(1) });
+
+        if server == *"--tcp" {
+            Server::Tcp(socket_address)
+        } else {
+            let mut ca = None;
+            let mut certificate = None;
+            let mut private_key = None;
+            loop {
+                let option = match args.peek() {
+                    Some(option) if *option == *"--ca" => &mut ca,
+                    Some(option) if *option == *"--cert" => &mut certificate,
+                    Some(option) if *option == *"--key" => &mut private_key,
+                    _ => break,
+                };
+                args.next();
+                *option = Some(PathBuf::from(args.next().unwrap_or_else(|| print_help(&program_path))));
+            }
+
+            let identity = match (&certificate, &private_key) {
+                (Some(certificate), Some(private_key)) => Some((certificate.as_path(), private_key.as_path())),
+                (None, None) => None,
+                _ => print_help(&program_path),
+            };
+
+            let connector = dscfg_client::tls::connector(ca.as_ref().map(AsRef::as_ref), identity)
+                .unwrap_or_else(|err| { eprintln!("Failed to set up TLS: {}", err); std::process::exit(1) });
+
+            // The port is always present, since the address resolved.
+            let host = &address[..address.rfind(':').unwrap_or(address.len())];
+            let domain = host.trim_start_matches('[').trim_end_matches(']').to_owned();
+
+            Server::Tls { address: socket_address, domain, connector }
+        }
+    } else {
+        Server::Unix(server)
+    };
     let operation = args.next().unwrap_or_else(|| print_help(&program_path));
     if operation == *"set" {
         let key = args
@@ -34,52 +129,132 @@ fn main() {
         let value = serde_json::from_str::<serde_json::Value>(&value)
             .unwrap_or_else(|err| { println!("Value isn't valid JSON: {}", err); print_help(&program_path); });
 
-        let client = tokio::net::unix::UnixStream::connect(socket_path)
+        let client = connect(server)
+            .map_err(dscfg_client::ProtocolError::Communication)
+            .and_then(dscfg_client::new)
             .and_then(|client| {
-                dscfg_client::new(client)
+                client
                     .set_value(key, value)
                     .map(std::mem::drop)
             })
-            .or_else(|err| Ok(println!("Setting value failed: {:?}", err)));
+            .or_else(|err| -> Result<(), ()> { eprintln!("Setting value failed: {}", err); std::process::exit(1) });
         tokio::run(client);
     } else if operation == *"listen" {
-        let key = args
-            .next()
-            .unwrap_or_else(|| print_help(&program_path))
-            .into_string()
-            .unwrap_or_else(|_| { println!("Key isn't a UTF-8 string"); print_help(&program_path); });
+        let patterns = args
+            .map(|key| {
+                let key = key
+                    .into_string()
+                    .unwrap_or_else(|_| { println!("Key isn't a UTF-8 string"); print_help(&program_path); });
+
+                if key.contains(['*', '?']) {
+                    dscfg_client::Pattern::Glob(key)
+                } else {
+                    dscfg_client::Pattern::Key(key)
+                }
+            })
+            .collect::<Vec<_>>();
 
-        let client = tokio::net::unix::UnixStream::connect(socket_path)
+        if patterns.is_empty() {
+            print_help(&program_path);
+        }
+
+        let client = connect(server)
+            .map_err(dscfg_client::ProtocolError::Communication)
+            .and_then(dscfg_client::new::<serde_json::Value, _>)
             .and_then(|client| {
-                dscfg_client::new::<serde_json::Value, _>(client)
-                    .listen_notifications(key, true)
+                client
+                    .listen_notifications_many(patterns, true)
                     .for_each(|(key, value)| {
-                        println!("The value of {} changed to {}", key, value);
+                        match value {
+                            Some(value) => println!("The value of {} changed to {}", key, value),
+                            None => println!("The key {} was deleted", key),
+                        }
                         Ok(())
                     })
             })
-            .or_else(|err| Ok(eprintln!("Waiting for notifications failed: {:?}", err)));
+            .or_else(|err| -> Result<(), ()> { eprintln!("Waiting for notifications failed: {}", err); std::process::exit(1) });
         tokio::run(client);
     } else if operation == *"get" {
+        let keys = args
+            .map(|key| key.into_string().unwrap_or_else(|_| { println!("Key isn't a UTF-8 string"); print_help(&program_path); }))
+            .collect::<Vec<_>>();
+
+        if keys.is_empty() {
+            print_help(&program_path);
+        }
+
+        // A single key is printed without its name for easier use in scripts.
+        let print_keys = keys.len() > 1;
+
+        let client = connect(server)
+            .map_err(dscfg_client::ProtocolError::Communication)
+            .and_then(dscfg_client::new::<serde_json::Value, _>)
+            .and_then(move |client| {
+                client
+                    .get_values(keys.clone())
+                    .map(move |(values, _)| {
+                        let mut missing = false;
+
+                        for (key, value) in keys.iter().zip(values) {
+                            match value {
+                                Some(value) if print_keys => println!("{} {}", key, value),
+                                Some(value) => println!("{}", value),
+                                None => {
+                                    eprintln!("The key {} doesn't exist", key);
+                                    missing = true;
+                                },
+                            }
+                        }
+
+                        if missing {
+                            std::process::exit(2);
+                        }
+                    })
+            })
+            .or_else(|err| -> Result<(), ()> { eprintln!("Getting value failed: {}", err); std::process::exit(1) });
+        tokio::run(client);
+    } else if operation == *"delete" {
         let key = args
             .next()
             .unwrap_or_else(|| print_help(&program_path))
             .into_string()
             .unwrap_or_else(|_| { println!("Key isn't a UTF-8 string"); print_help(&program_path); });
 
-        let client = tokio::net::unix::UnixStream::connect(socket_path)
+        let client = connect(server)
             .map_err(dscfg_client::ProtocolError::Communication)
-            .and_then(|client| {
-                dscfg_client::new::<serde_json::Value, _>(client)
-                    .get_value(key)
-                    .and_then(|(value, _)| {
-                        serde_json::to_string(&value)
-                              .map_err(Into::into)
-                              .map_err(dscfg_client::ProtocolError::Communication)
-                    })
-                   .map(|value| println!("{}", value))
+            .and_then(dscfg_client::new::<serde_json::Value, _>)
+            .and_then(|client| client.delete_value(key))
+            .map(|(deleted, _)| if !deleted {
+                eprintln!("The key doesn't exist");
+                std::process::exit(2);
             })
-            .or_else(|err| Ok(eprintln!("Getting value failed: {:?}", err)));
+            .or_else(|err| -> Result<(), ()> { eprintln!("Deleting value failed: {}", err); std::process::exit(1) });
+        tokio::run(client);
+    } else if operation == *"list" {
+        let prefix = args
+            .next()
+            .map(|prefix| prefix.into_string().unwrap_or_else(|_| { println!("Prefix isn't a UTF-8 string"); print_help(&program_path); }))
+            .unwrap_or_default();
+
+        let client = connect(server)
+            .map_err(dscfg_client::ProtocolError::Communication)
+            .and_then(dscfg_client::new::<serde_json::Value, _>)
+            .and_then(|client| client.list_keys(prefix))
+            .map(|(keys, _)| for key in keys { println!("{}", key) })
+            .or_else(|err| -> Result<(), ()> { eprintln!("Listing keys failed: {}", err); std::process::exit(1) });
+        tokio::run(client);
+    } else if operation == *"dump" {
+        let prefix = args
+            .next()
+            .map(|prefix| prefix.into_string().unwrap_or_else(|_| { println!("Prefix isn't a UTF-8 string"); print_help(&program_path); }))
+            .unwrap_or_default();
+
+        let client = connect(server)
+            .map_err(dscfg_client::ProtocolError::Communication)
+            .and_then(dscfg_client::new::<serde_json::Value, _>)
+            .and_then(|client| client.get_prefix(prefix))
+            .map(|(values, _)| for (key, value) in values { println!("{} {}", key, value) })
+            .or_else(|err| -> Result<(), ()> { eprintln!("Dumping values failed: {}", err); std::process::exit(1) });
         tokio::run(client);
     } else {
         print_help(&program_path);